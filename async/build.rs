@@ -1,6 +1,7 @@
 extern crate amq_protocol;
 
 use amq_protocol::codegen::*;
+use amq_protocol::types::AMQPType;
 
 use std::collections::BTreeMap;
 use std::env;
@@ -25,8 +26,57 @@ fn main() {
     handlebars.register_template_string("full", full_tpl).expect("Failed to register full template");
     handlebars.register_template_string("api", api_tpl).expect("Failed to register api template");
 
-    let specs     = AMQProtocolDefinition::load();
+    let mut specs = AMQProtocolDefinition::load();
+    add_update_secret(&mut specs);
+    binary_sasl_data(&mut specs);
     data.insert("specs".to_string(), specs);
     writeln!(f, "{}", handlebars.render("full", &data).expect("Failed to render full template")).expect("Failed to write generated.rs");
     writeln!(f2, "{}", handlebars.render("api", &data).expect("Failed to render api template")).expect("Failed to write api.rs");
 }
+
+/// SASL challenges and responses are binary data, like the AMQPLAIN field table,
+/// so they are handled as byte arrays, encoded on the wire like long strings
+fn binary_sasl_data(specs: &mut AMQProtocolDefinition) {
+    let connection = specs.classes.iter_mut().find(|c| c.name == "connection").expect("no connection class in the specs");
+    for method in connection.methods.iter_mut().filter(|m| m.name == "start-ok" || m.name == "secure" || m.name == "secure-ok") {
+        for argument in &mut method.arguments {
+            if let AMQPArgument::Value(ref mut value) = *argument {
+                if value.name == "response" || value.name == "challenge" {
+                    value.amqp_type = AMQPType::ByteArray;
+                }
+            }
+        }
+    }
+}
+
+/// RabbitMQ's connection.update-secret extension is not part of the bundled specs
+fn add_update_secret(specs: &mut AMQProtocolDefinition) {
+    let connection = specs.classes.iter_mut().find(|c| c.name == "connection").expect("no connection class in the specs");
+    if connection.methods.iter().any(|m| m.name == "update-secret") {
+        return;
+    }
+
+    let argument = |amqp_type, name: &str| AMQPArgument::Value(AMQPValueArgument {
+        amqp_type,
+        name:          name.to_string(),
+        default_value: None,
+        domain:        None,
+    });
+
+    connection.methods.push(AMQPMethod {
+        id:            70,
+        arguments:     vec![argument(AMQPType::LongString, "new-secret"), argument(AMQPType::ShortString, "reason")],
+        has_arguments: true,
+        has_flags:     false,
+        name:          "update-secret".to_string(),
+        synchronous:   true,
+    });
+    connection.methods.push(AMQPMethod {
+        id:            71,
+        arguments:     vec![],
+        has_arguments: false,
+        has_flags:     false,
+        name:          "update-secret-ok".to_string(),
+        synchronous:   false,
+    });
+}
@@ -80,7 +80,7 @@ fn main() {
 
       info!("will publish");
       conn.basic_publish(channel_a, 0, "".to_string(), "hello".to_string(), false, false).expect("basic_publish");
-      let payload = b"Hello world!";
+      let payload = &b"Hello world!"[..];
       conn.send_content_frames(channel_a, 60, payload, basic::Properties::default());
       info!("[{}] state: {:?}", line!(), conn.run(&mut stream, &mut send_buffer, &mut receive_buffer).unwrap());
       thread::sleep(time::Duration::from_millis(100));
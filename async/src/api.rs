@@ -4,7 +4,7 @@ use message::*;
 use generated::*;
 use error::*;
 use types::*;
-use std::collections::{HashSet,VecDeque};
+use std::collections::VecDeque;
 
 #[derive(Clone,Debug,PartialEq,Eq)]
 pub enum ChannelState {
@@ -15,6 +15,8 @@ pub enum ChannelState {
     SendingContent(usize),
     WillReceiveContent(String,Option<String>),
     ReceivingContent(String,Option<String>,usize),
+    WillReceiveReturn,
+    ReceivingReturn(usize),
 }
 
 pub type RequestId = u64;
@@ -50,7 +52,9 @@ pub enum Answer {
 
     // RabbitMQ confirm extension
     AwaitingConfirmSelectOk(RequestId),
-    AwaitingPublishConfirm(RequestId),
+
+    // RabbitMQ update-secret extension
+    AwaitingUpdateSecretOk(RequestId),
 }
 
 impl Connection {
@@ -102,11 +106,13 @@ impl Connection {
             Class::Basic(basic::Methods::ConsumeOk(m)) => {
                 self.receive_basic_consume_ok(channel_id, m)
             }
+            Class::Basic(basic::Methods::Cancel(m)) => self.receive_basic_cancel(channel_id, m),
             Class::Basic(basic::Methods::CancelOk(m)) => {
                 self.receive_basic_cancel_ok(channel_id, m)
             }
             Class::Basic(basic::Methods::Deliver(m)) => self.receive_basic_deliver(channel_id, m),
             Class::Basic(basic::Methods::GetOk(m)) => self.receive_basic_get_ok(channel_id, m),
+            Class::Basic(basic::Methods::Return(m)) => self.receive_basic_return(channel_id, m),
             Class::Basic(basic::Methods::GetEmpty(m)) => {
                 self.receive_basic_get_empty(channel_id, m)
             }
@@ -114,11 +120,9 @@ impl Connection {
                 self.receive_basic_recover_ok(channel_id, m)
             }
 
-            /*
             Class::Tx(tx::Methods::SelectOk(m)) => self.receive_tx_select_ok(channel_id, m),
             Class::Tx(tx::Methods::CommitOk(m)) => self.receive_tx_commit_ok(channel_id, m),
             Class::Tx(tx::Methods::RollbackOk(m)) => self.receive_tx_rollback_ok(channel_id, m),
-            */
 
             Class::Confirm(confirm::Methods::SelectOk(m)) => {
                 self.receive_confirm_select_ok(channel_id, m)
@@ -302,7 +306,7 @@ impl Connection {
 
     pub fn receive_channel_close(&mut self,
                                  _channel_id: u16,
-                                 _: channel::Close)
+                                 method: channel::Close)
                                  -> Result<(), Error> {
 
         if !self.channels.contains_key(&_channel_id) {
@@ -314,12 +318,21 @@ impl Connection {
             return Err(Error::NotConnected);
         }
 
-        //FIXME: log the error if there is one
-        //FIXME: handle reply codes
+        error!("server closed channel {}: {} {} (class {}, method {})", _channel_id,
+               method.reply_code, method.reply_text, method.class_id, method.method_id);
+
+        let res = self.channel_close_ok(_channel_id);
 
-        self.get_next_answer(_channel_id);
-        self.set_channel_state(_channel_id, ChannelState::Closed);
-        self.channel_close_ok(_channel_id)
+        // free the channel and keep the reason for the handles still using this id
+        let serial = self.channels.get(&_channel_id).map(|c| c.serial).unwrap_or(0);
+        self.remove_channel(_channel_id);
+        self.closed_channels.insert(_channel_id, (serial, CloseReason {
+            reply_code: method.reply_code,
+            reply_text: method.reply_text,
+            class_id:   method.class_id,
+            method_id:  method.method_id,
+        }));
+        res
     }
 
     pub fn channel_close_ok(&mut self, _channel_id: u16) -> Result<(), Error> {
@@ -353,7 +366,7 @@ impl Connection {
         match self.get_next_answer(_channel_id) {
           Some(Answer::AwaitingChannelCloseOk(request_id)) => {
             self.finished_reqs.insert(request_id, true);
-            self.set_channel_state(_channel_id, ChannelState::Closed);
+            self.remove_channel(_channel_id);
           },
           _ => {
             self.set_channel_state(_channel_id, ChannelState::Error);
@@ -577,6 +590,8 @@ impl Connection {
             return Err(Error::NotConnected);
         }
 
+        self.check_capability("exchange_exchange_bindings")?;
+
         let method = Class::Exchange(exchange::Methods::Bind(exchange::Bind {
             ticket: ticket,
             destination: destination,
@@ -641,6 +656,8 @@ impl Connection {
             return Err(Error::NotConnected);
         }
 
+        self.check_capability("exchange_exchange_bindings")?;
+
         let method = Class::Exchange(exchange::Methods::Unbind(exchange::Unbind {
             ticket: ticket,
             destination: destination,
@@ -942,7 +959,10 @@ impl Connection {
         match self.get_next_answer(_channel_id) {
           Some(Answer::AwaitingQueueDeleteOk(request_id, key)) => {
             self.finished_reqs.insert(request_id, true);
-            self.channels.get_mut(&_channel_id).map(|c| c.queues.remove(&key));
+            if let Some(c) = self.channels.get_mut(&_channel_id) {
+              c.queues.remove(&key);
+              c.consumer_index.retain(|_, queue_name| *queue_name != key);
+            }
             Ok(())
           },
           _ => {
@@ -1156,12 +1176,14 @@ impl Connection {
                   nowait:          nowait,
                   current_message: None,
                   messages:        VecDeque::new(),
+                  cancelled:       false,
                 };
                 q.consumers.insert(
                   method.consumer_tag.clone(),
                   consumer
                   )
-              })
+              });
+              c.consumer_index.insert(method.consumer_tag.clone(), queue.clone());
             });
             Ok(())
           },
@@ -1201,6 +1223,40 @@ impl Connection {
         })
     }
 
+    pub fn receive_basic_cancel(&mut self,
+                                _channel_id: u16,
+                                method: basic::Cancel)
+                                -> Result<(), Error> {
+
+        if !self.channels.contains_key(&_channel_id) {
+            trace!("key {} not in channels {:?}", _channel_id, self.channels);
+            return Err(Error::InvalidChannel);
+        }
+
+        if !self.is_connected(_channel_id) {
+            return Err(Error::NotConnected);
+        }
+
+        warn!("server cancelled consumer {} on channel {}", method.consumer_tag, _channel_id);
+
+        if let Some(c) = self.channels.get_mut(&_channel_id) {
+            if let Some(queue_name) = c.consumer_index.remove(&method.consumer_tag) {
+                if let Some(q) = c.queues.get_mut(&queue_name) {
+                    q.cancel_consumer(&method.consumer_tag);
+                }
+            }
+        }
+
+        if method.nowait {
+            Ok(())
+        } else {
+            let method = Class::Basic(basic::Methods::CancelOk(basic::CancelOk {
+                consumer_tag: method.consumer_tag,
+            }));
+            self.send_method_frame(_channel_id, method)
+        }
+    }
+
     pub fn receive_basic_cancel_ok(&mut self,
                                    _channel_id: u16,
                                    method: basic::CancelOk)
@@ -1218,11 +1274,13 @@ impl Connection {
         match self.get_next_answer(_channel_id) {
           Some(Answer::AwaitingBasicCancelOk(request_id)) => {
             self.finished_reqs.insert(request_id, true);
-            self.channels.get_mut(&_channel_id).map(|c| {
-              for ref mut q in c.queues.values_mut() {
-                q.consumers.remove(&method.consumer_tag);
+            if let Some(c) = self.channels.get_mut(&_channel_id) {
+              if let Some(queue_name) = c.consumer_index.remove(&method.consumer_tag) {
+                if let Some(q) = c.queues.get_mut(&queue_name) {
+                  q.consumers.remove(&method.consumer_tag);
+                }
               }
-            });
+            }
             Ok(())
           },
           _ => {
@@ -1249,6 +1307,10 @@ impl Connection {
             return Err(Error::NotConnected);
         }
 
+        if !self.channels[&_channel_id].send_flow {
+            return Err(Error::FlowStopped);
+        }
+
         let method = Class::Basic(basic::Methods::Publish(basic::Publish {
             ticket: ticket,
             exchange: exchange,
@@ -1258,12 +1320,10 @@ impl Connection {
         }));
 
         self.send_method_frame(_channel_id, method).map(|_| {
-            //FIXME: if we're not on a confirm channel, we're jumping over some request id
-            // this is not a big issue, since we only need them to be unique
-            let request_id = self.next_request_id();
+            // confirms are not tracked in `awaiting`: they can come after the answers
+            // to later methods, and a single ack can cover several messages
             self.channels.get_mut(&_channel_id).map(|c| {
               if c.confirm {
-                c.awaiting.push_back(Answer::AwaitingPublishConfirm(request_id));
                 let delivery_tag = c.message_count;
                 c.unacked.insert(delivery_tag);
                 c.message_count += 1;
@@ -1287,20 +1347,58 @@ impl Connection {
             return Err(Error::NotConnected);
         }
 
-        self.channels.get_mut(&_channel_id).map(|c| {
-            for (ref queue_name, ref mut q) in &mut c.queues {
-              c.state = ChannelState::WillReceiveContent(queue_name.to_string(), Some(method.consumer_tag.to_string()));
-              q.consumers.get_mut(&method.consumer_tag).map(|cs| {
-                cs.current_message = Some(Delivery::new(
-                  method.delivery_tag,
-                  method.exchange.to_string(),
-                  method.routing_key.to_string(),
-                  method.redelivered
-                ));
-              });
-            }
+        if let Some(c) = self.channels.get_mut(&_channel_id) {
+            let queue_name = match c.consumer_index.get(&method.consumer_tag) {
+              Some(queue_name) => {
+                if let Some(cs) = c.queues.get_mut(queue_name).and_then(|q| q.consumers.get_mut(&method.consumer_tag)) {
+                  cs.current_message = Some(Delivery::new(
+                    method.delivery_tag,
+                    method.exchange.to_string(),
+                    method.routing_key.to_string(),
+                    method.redelivered
+                  ));
+                }
+                queue_name.to_string()
+              },
+              None             => {
+                // the content frames will still be read, but dropped
+                warn!("channel {}: delivery {} for unknown consumer {}", _channel_id, method.delivery_tag, method.consumer_tag);
+                String::new()
+              },
+            };
+            c.state = ChannelState::WillReceiveContent(queue_name, Some(method.consumer_tag.to_string()));
+            trace!("channel {} state is now {:?}", _channel_id, c.state);
+        }
+        Ok(())
+    }
+
+    pub fn receive_basic_return(&mut self,
+                                _channel_id: u16,
+                                method: basic::Return)
+                                -> Result<(), Error> {
+
+        if !self.channels.contains_key(&_channel_id) {
+            trace!("key {} not in channels {:?}", _channel_id, self.channels);
+            return Err(Error::InvalidChannel);
+        }
+
+        if !self.is_connected(_channel_id) {
+            return Err(Error::NotConnected);
+        }
+
+        warn!("channel {}: message published to exchange {:?} with routing key {:?} was returned: {} {}",
+              _channel_id, method.exchange, method.routing_key, method.reply_code, method.reply_text);
+
+        if let Some(c) = self.channels.get_mut(&_channel_id) {
+            c.state = ChannelState::WillReceiveReturn;
+            c.current_return = Some(BasicReturnMessage::new(
+              method.exchange.to_string(),
+              method.routing_key.to_string(),
+              method.reply_code,
+              method.reply_text.to_string()
+            ));
             trace!("channel {} state is now {:?}", _channel_id, c.state);
-        });
+        }
         Ok(())
     }
 
@@ -1522,6 +1620,8 @@ impl Connection {
             return Err(Error::NotConnected);
         }
 
+        self.check_capability("basic.nack")?;
+
         let method = Class::Basic(basic::Methods::Nack(basic::Nack {
             delivery_tag: delivery_tag,
             multiple: multiple,
@@ -1539,6 +1639,12 @@ impl Connection {
             return Err(Error::NotConnected);
         }
 
+        self.check_capability("publisher_confirms")?;
+
+        if self.channels.get(&_channel_id).map(|c| c.tx_requested()).unwrap_or(false) {
+            return Err(Error::ConfirmAndTxMode);
+        }
+
         let method = Class::Confirm(confirm::Methods::Select(confirm::Select { nowait: nowait }));
 
         self.send_method_frame(_channel_id, method).map(|_| {
@@ -1581,12 +1687,35 @@ impl Connection {
         }
     }
 
-    pub fn receive_basic_ack(&mut self,
-                     _channel_id: u16,
-                     method: basic::Ack)
-                     -> Result<(), Error> {
+    pub fn tx_select(&mut self, _channel_id: u16) -> Result<RequestId, Error> {
+        if !self.channels.contains_key(&_channel_id) {
+            return Err(Error::InvalidChannel);
+        }
+
+        if !self.is_connected(_channel_id) {
+            return Err(Error::NotConnected);
+        }
+
+        if self.channels.get(&_channel_id).map(|c| c.confirm_requested()).unwrap_or(false) {
+            return Err(Error::ConfirmAndTxMode);
+        }
+
+        let method = Class::Tx(tx::Methods::Select(tx::Select {}));
+
+        self.send_method_frame(_channel_id, method).map(|_| {
+            let request_id = self.next_request_id();
+            self.push_back_answer(_channel_id, Answer::AwaitingTxSelectOk(request_id));
+            request_id
+        })
+    }
+
+    pub fn receive_tx_select_ok(&mut self,
+                                _channel_id: u16,
+                                _: tx::SelectOk)
+                                -> Result<(), Error> {
 
         if !self.channels.contains_key(&_channel_id) {
+            trace!("key {} not in channels {:?}", _channel_id, self.channels);
             return Err(Error::InvalidChannel);
         }
 
@@ -1595,38 +1724,45 @@ impl Connection {
         }
 
         match self.get_next_answer(_channel_id) {
-          Some(Answer::AwaitingPublishConfirm(request_id)) => {
+          Some(Answer::AwaitingTxSelectOk(request_id)) => {
             self.finished_reqs.insert(request_id, true);
-
-            self.channels.get_mut(&_channel_id).map(|c| {
-              if c.confirm {
-                if method.multiple {
-                  let h: HashSet<u64> = c.unacked.iter().filter(|elem| *elem <= &method.delivery_tag).cloned().collect();
-                  c.unacked = c.unacked.difference(&h).cloned().collect();
-                  c.acked = c.acked.union(&h).cloned().collect();
-                } else {
-                  if c.unacked.remove(&method.delivery_tag) {
-                    c.acked.insert(method.delivery_tag);
-                  }
-                }
-              }
-            });
-
+            if let Some(c) = self.channels.get_mut(&_channel_id) {
+              c.tx = true;
+            }
             Ok(())
           },
           _ => {
             self.set_channel_state(_channel_id, ChannelState::Error);
-            return Err(Error::UnexpectedAnswer);
+            Err(Error::UnexpectedAnswer)
           }
         }
     }
 
-    pub fn receive_basic_nack(&mut self,
-                      _channel_id: u16,
-                      method: basic::Nack)
-                      -> Result<(), Error> {
+    pub fn tx_commit(&mut self, _channel_id: u16) -> Result<RequestId, Error> {
+        if !self.channels.contains_key(&_channel_id) {
+            return Err(Error::InvalidChannel);
+        }
+
+        if !self.is_connected(_channel_id) {
+            return Err(Error::NotConnected);
+        }
+
+        let method = Class::Tx(tx::Methods::Commit(tx::Commit {}));
+
+        self.send_method_frame(_channel_id, method).map(|_| {
+            let request_id = self.next_request_id();
+            self.push_back_answer(_channel_id, Answer::AwaitingTxCommitOk(request_id));
+            request_id
+        })
+    }
+
+    pub fn receive_tx_commit_ok(&mut self,
+                                _channel_id: u16,
+                                _: tx::CommitOk)
+                                -> Result<(), Error> {
 
         if !self.channels.contains_key(&_channel_id) {
+            trace!("key {} not in channels {:?}", _channel_id, self.channels);
             return Err(Error::InvalidChannel);
         }
 
@@ -1635,30 +1771,105 @@ impl Connection {
         }
 
         match self.get_next_answer(_channel_id) {
-          Some(Answer::AwaitingPublishConfirm(request_id)) => {
+          Some(Answer::AwaitingTxCommitOk(request_id)) => {
             self.finished_reqs.insert(request_id, true);
+            Ok(())
+          },
+          _ => {
+            self.set_channel_state(_channel_id, ChannelState::Error);
+            Err(Error::UnexpectedAnswer)
+          }
+        }
+    }
 
-            self.channels.get_mut(&_channel_id).map(|c| {
-              if c.confirm {
-                if method.multiple {
-                  let h: HashSet<u64> = c.unacked.iter().filter(|elem| *elem <= &method.delivery_tag).cloned().collect();
-                  c.unacked = c.unacked.difference(&h).cloned().collect();
-                  c.acked = c.nacked.union(&h).cloned().collect();
-                } else {
-                  if c.unacked.remove(&method.delivery_tag) {
-                    c.nacked.insert(method.delivery_tag);
-                  }
-                }
-              }
-            });
+    pub fn tx_rollback(&mut self, _channel_id: u16) -> Result<RequestId, Error> {
+        if !self.channels.contains_key(&_channel_id) {
+            return Err(Error::InvalidChannel);
+        }
 
+        if !self.is_connected(_channel_id) {
+            return Err(Error::NotConnected);
+        }
+
+        let method = Class::Tx(tx::Methods::Rollback(tx::Rollback {}));
+
+        self.send_method_frame(_channel_id, method).map(|_| {
+            let request_id = self.next_request_id();
+            self.push_back_answer(_channel_id, Answer::AwaitingTxRollbackOk(request_id));
+            request_id
+        })
+    }
+
+    pub fn receive_tx_rollback_ok(&mut self,
+                                  _channel_id: u16,
+                                  _: tx::RollbackOk)
+                                  -> Result<(), Error> {
+
+        if !self.channels.contains_key(&_channel_id) {
+            trace!("key {} not in channels {:?}", _channel_id, self.channels);
+            return Err(Error::InvalidChannel);
+        }
+
+        if !self.is_connected(_channel_id) {
+            return Err(Error::NotConnected);
+        }
+
+        match self.get_next_answer(_channel_id) {
+          Some(Answer::AwaitingTxRollbackOk(request_id)) => {
+            self.finished_reqs.insert(request_id, true);
             Ok(())
           },
           _ => {
             self.set_channel_state(_channel_id, ChannelState::Error);
-            return Err(Error::UnexpectedAnswer);
+            Err(Error::UnexpectedAnswer)
           }
         }
     }
 
+    pub fn receive_basic_ack(&mut self,
+                     _channel_id: u16,
+                     method: basic::Ack)
+                     -> Result<(), Error> {
+
+        if !self.channels.contains_key(&_channel_id) {
+            return Err(Error::InvalidChannel);
+        }
+
+        if !self.is_connected(_channel_id) {
+            return Err(Error::NotConnected);
+        }
+
+        if let Some(c) = self.channels.get_mut(&_channel_id) {
+            if !c.confirm {
+                c.state = ChannelState::Error;
+                return Err(Error::UnexpectedAnswer);
+            }
+            c.receive_ack(method.delivery_tag, method.multiple);
+        }
+        Ok(())
+    }
+
+    pub fn receive_basic_nack(&mut self,
+                      _channel_id: u16,
+                      method: basic::Nack)
+                      -> Result<(), Error> {
+
+        if !self.channels.contains_key(&_channel_id) {
+            return Err(Error::InvalidChannel);
+        }
+
+        if !self.is_connected(_channel_id) {
+            return Err(Error::NotConnected);
+        }
+
+        if let Some(c) = self.channels.get_mut(&_channel_id) {
+            if !c.confirm {
+                c.state = ChannelState::Error;
+                return Err(Error::UnexpectedAnswer);
+            }
+            c.receive_nack(method.delivery_tag, method.multiple);
+        }
+        Ok(())
+    }
+
 }
@@ -0,0 +1,227 @@
+/// SASL mechanisms used during the connection handshake
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc,Mutex};
+use sasl::client::Mechanism;
+use sasl::client::mechanisms::Plain;
+use sasl::common::{Credentials,Identity,Password,Secret};
+
+/// authentication mechanisms supported by the client
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum SASLMechanism {
+  /// RabbitMQ's non standard mechanism, sending the credentials as a field table
+  AMQPlain,
+  /// authentication handled outside of AMQP, usually with a TLS client certificate
+  External,
+  /// username and password, as defined by RFC 4616
+  Plain,
+  /// a mechanism implemented outside of lapin
+  Custom(CustomMechanism),
+}
+
+impl SASLMechanism {
+  /// name of the mechanism, as listed by the server in `connection.start`
+  pub fn name(&self) -> &str {
+    match *self {
+      SASLMechanism::AMQPlain       => "AMQPLAIN",
+      SASLMechanism::External       => "EXTERNAL",
+      SASLMechanism::Plain          => "PLAIN",
+      SASLMechanism::Custom(ref m)  => &m.name,
+    }
+  }
+
+  /// creates the client side implementation of this mechanism
+  pub fn client(&self, credentials: Credentials) -> Result<Box<dyn Mechanism + Send>, String> {
+    match *self {
+      SASLMechanism::AMQPlain      => AMQPlain::from_credentials(credentials).map(|m| Box::new(m) as Box<dyn Mechanism + Send>),
+      SASLMechanism::External      => External::from_credentials(credentials).map(|m| Box::new(m) as Box<dyn Mechanism + Send>),
+      SASLMechanism::Plain         => Plain::from_credentials(credentials).map(|m| Box::new(m) as Box<dyn Mechanism + Send>),
+      SASLMechanism::Custom(ref m) => (m.builder)(credentials),
+    }
+  }
+}
+
+/// builds the client side of a mechanism from the connection's credentials
+pub type MechanismBuilder = dyn Fn(Credentials) -> Result<Box<dyn Mechanism + Send>, String> + Send + Sync;
+
+/// a SASL mechanism that is not provided by lapin
+///
+/// the mechanism gets the server's challenges from `connection.secure`
+/// through `Mechanism::response`, for as many rounds as the server needs
+#[derive(Clone)]
+pub struct CustomMechanism {
+  name:    String,
+  builder: Arc<MechanismBuilder>,
+}
+
+impl CustomMechanism {
+  /// `name` is the mechanism's name as listed by the server, and `builder` is
+  /// called to create a new client for each connection
+  pub fn new<F>(name: &str, builder: F) -> CustomMechanism
+    where F: Fn(Credentials) -> Result<Box<dyn Mechanism + Send>, String> + Send + Sync + 'static {
+    CustomMechanism {
+      name:    name.to_string(),
+      builder: Arc::new(builder),
+    }
+  }
+}
+
+impl fmt::Debug for CustomMechanism {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("CustomMechanism").field("name", &self.name).finish()
+  }
+}
+
+impl PartialEq for CustomMechanism {
+  fn eq(&self, other: &CustomMechanism) -> bool {
+    self.name == other.name
+  }
+}
+
+impl Eq for CustomMechanism {}
+
+/// the SASL exchange in progress during the connection handshake
+#[derive(Clone)]
+pub struct SASLSession {
+  name:      String,
+  mechanism: Arc<Mutex<Box<dyn Mechanism + Send>>>,
+}
+
+impl SASLSession {
+  pub fn new(mechanism: &SASLMechanism, credentials: Credentials) -> Result<SASLSession, String> {
+    mechanism.client(credentials).map(|client| SASLSession {
+      name:      mechanism.name().to_string(),
+      mechanism: Arc::new(Mutex::new(client)),
+    })
+  }
+
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  /// response sent in `connection.start-ok`
+  pub fn initial(&self) -> Result<Vec<u8>, String> {
+    self.mechanism.lock().map_err(|_| "SASL mechanism mutex is poisoned".to_string())?.initial()
+  }
+
+  /// response to a `connection.secure` challenge, sent in `connection.secure-ok`
+  pub fn response(&self, challenge: &[u8]) -> Result<Vec<u8>, String> {
+    self.mechanism.lock().map_err(|_| "SASL mechanism mutex is poisoned".to_string())?.response(challenge)
+  }
+}
+
+impl fmt::Debug for SASLSession {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("SASLSession").field("name", &self.name).finish()
+  }
+}
+
+impl FromStr for SASLMechanism {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.to_uppercase().as_str() {
+      "AMQPLAIN" => Ok(SASLMechanism::AMQPlain),
+      "EXTERNAL" => Ok(SASLMechanism::External),
+      "PLAIN"    => Ok(SASLMechanism::Plain),
+      _          => Err(format!("Unsupported SASL mechanism: '{}'", s)),
+    }
+  }
+}
+
+/// picks the first mechanism of `preferred` that appears in the
+/// space separated list of mechanisms sent by the server
+pub fn select_mechanism(preferred: &[SASLMechanism], server_mechanisms: &str) -> Option<SASLMechanism> {
+  preferred.iter().find(|mechanism| {
+    server_mechanisms.split_whitespace().any(|name| name == mechanism.name())
+  }).cloned()
+}
+
+/// the AMQPLAIN mechanism
+///
+/// the response is a field table, without its length prefix, with
+/// the `LOGIN` and `PASSWORD` keys
+pub struct AMQPlain {
+  username: String,
+  password: String,
+}
+
+impl Mechanism for AMQPlain {
+  fn name(&self) -> &str { "AMQPLAIN" }
+
+  fn from_credentials(credentials: Credentials) -> Result<AMQPlain, String> {
+    match (credentials.identity, credentials.secret) {
+      (Identity::Username(username), Secret::Password(Password::Plain(password))) => {
+        Ok(AMQPlain { username, password })
+      },
+      _ => Err("AMQPLAIN requires a username and a plaintext password".to_string()),
+    }
+  }
+
+  fn initial(&mut self) -> Result<Vec<u8>, String> {
+    let mut auth = Vec::new();
+    gen_long_string_entry(&mut auth, "LOGIN", &self.username)?;
+    gen_long_string_entry(&mut auth, "PASSWORD", &self.password)?;
+    Ok(auth)
+  }
+}
+
+fn gen_long_string_entry(out: &mut Vec<u8>, key: &str, value: &str) -> Result<(), String> {
+  if key.len() > u8::max_value() as usize {
+    return Err(format!("field table key too long: {}", key));
+  }
+  out.push(key.len() as u8);
+  out.extend(key.bytes());
+  out.push(b'S');
+  let len = value.len() as u32;
+  out.extend(&[(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8]);
+  out.extend(value.bytes());
+  Ok(())
+}
+
+/// the EXTERNAL mechanism
+///
+/// the server authenticates the client through other means, like
+/// the certificate presented during the TLS handshake
+pub struct External;
+
+impl Mechanism for External {
+  fn name(&self) -> &str { "EXTERNAL" }
+
+  fn from_credentials(_: Credentials) -> Result<External, String> {
+    Ok(External)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn select_mechanism_preference_order() {
+    let preferred = [SASLMechanism::External, SASLMechanism::Plain];
+    assert_eq!(select_mechanism(&preferred, "AMQPLAIN PLAIN"), Some(SASLMechanism::Plain));
+    assert_eq!(select_mechanism(&preferred, "PLAIN EXTERNAL"), Some(SASLMechanism::External));
+    assert_eq!(select_mechanism(&preferred, "AMQPLAIN"), None);
+  }
+
+  #[test]
+  fn amqplain_response() {
+    let credentials = Credentials::default().with_username("guest").with_password("pass");
+    let mut mechanism = SASLMechanism::AMQPlain.client(credentials).unwrap();
+    assert_eq!(mechanism.initial().unwrap(), b"\x05LOGINS\x00\x00\x00\x05guest\x08PASSWORDS\x00\x00\x00\x04pass".to_vec());
+  }
+
+  #[test]
+  fn amqplain_response_with_long_password() {
+    // longer than 127 bytes and not ASCII, the length prefix is not valid UTF-8 on its own
+    let password = "mot de passe é".repeat(10) + "!!";
+    assert_eq!(password.len(), 152);
+    let credentials = Credentials::default().with_username("guest").with_password(password.as_str());
+    let session = SASLSession::new(&SASLMechanism::AMQPlain, credentials).unwrap();
+
+    let mut expected = b"\x05LOGINS\x00\x00\x00\x05guest\x08PASSWORDS\x00\x00\x00\x98".to_vec();
+    expected.extend(password.as_bytes());
+    assert_eq!(session.initial().unwrap(), expected);
+  }
+}
@@ -1,44 +1,63 @@
 use format::frame::Frame;
-use std::collections::{HashMap,HashSet,VecDeque};
+use std::collections::{BTreeSet,HashMap,VecDeque};
+use std::mem;
 use generated::Class;
 use api::{Answer,ChannelState};
 use queue::*;
+use message::BasicReturnMessage;
 
 #[derive(Clone,Debug,PartialEq)]
 pub struct Channel {
-  pub id:             u16,
-  pub state:          ChannelState,
-  pub frame_queue:    VecDeque<Frame>,
-  pub send_flow:      bool,
-  pub receive_flow:   bool,
-  pub queues:         HashMap<String, Queue>,
-  pub prefetch_size:  u32,
-  pub prefetch_count: u16,
-  pub awaiting:       VecDeque<Answer>,
-  pub confirm:        bool,
-  pub message_count:  u64,
-  pub acked:          HashSet<u64>,
-  pub nacked:         HashSet<u64>,
-  pub unacked:        HashSet<u64>,
+  pub id:              u16,
+  /// unique for the lifetime of the connection, tells apart channels reusing the same id
+  pub serial:          u64,
+  pub state:           ChannelState,
+  pub frame_queue:     VecDeque<Frame>,
+  pub send_flow:       bool,
+  pub receive_flow:    bool,
+  pub queues:          HashMap<String, Queue>,
+  /// queue name of each consumer tag on this channel
+  pub consumer_index:  HashMap<String, String>,
+  pub prefetch_size:   u32,
+  pub prefetch_count:  u16,
+  pub awaiting:        VecDeque<Answer>,
+  pub confirm:         bool,
+  pub tx:              bool,
+  pub message_count:   u64,
+  pub acked:           BTreeSet<u64>,
+  pub nacked:          BTreeSet<u64>,
+  pub unacked:         BTreeSet<u64>,
+  pub returned:        BTreeSet<u64>,
+  pub returns:         VecDeque<BasicReturnMessage>,
+  pub current_return:  Option<BasicReturnMessage>,
+  /// number of basic.return received that still wait for the confirm of their delivery tag
+  pub pending_returns: usize,
 }
 
 impl Channel {
   pub fn new(channel_id: u16) -> Channel {
     Channel {
-      id:             channel_id,
-      state:          ChannelState::Initial,
-      frame_queue:    VecDeque::new(),
-      send_flow:      true,
-      receive_flow:   true,
-      queues:         HashMap::new(),
-      prefetch_size:  0,
-      prefetch_count: 0,
-      awaiting:       VecDeque::new(),
-      confirm:        false,
-      message_count:  0,
-      acked:          HashSet::new(),
-      nacked:         HashSet::new(),
-      unacked:        HashSet::new(),
+      id:              channel_id,
+      serial:          0,
+      state:           ChannelState::Initial,
+      frame_queue:     VecDeque::new(),
+      send_flow:       true,
+      receive_flow:    true,
+      queues:          HashMap::new(),
+      consumer_index:  HashMap::new(),
+      prefetch_size:   0,
+      prefetch_count:  0,
+      awaiting:        VecDeque::new(),
+      confirm:         false,
+      tx:              false,
+      message_count:   0,
+      acked:           BTreeSet::new(),
+      nacked:          BTreeSet::new(),
+      unacked:         BTreeSet::new(),
+      returned:        BTreeSet::new(),
+      returns:         VecDeque::new(),
+      current_return:  None,
+      pending_returns: 0,
     }
   }
 
@@ -52,6 +71,81 @@ impl Channel {
     self.frame_queue.push_back(Frame::Method(self.id,m));
   }
 
+  /// queues the returned message once its content is complete
+  ///
+  /// on a confirm channel, the server sends basic.return right before the ack of the
+  /// same message, so it is attached to the delivery tag of the next ack or nack
+  pub fn complete_returned_message(&mut self) {
+    if let Some(message) = self.current_return.take() {
+      if self.confirm {
+        self.pending_returns += 1;
+      }
+      self.returns.push_back(message);
+    }
+  }
+
+  /// moves the delivery tags confirmed by a basic.ack out of `unacked` and into `acked`
+  pub fn receive_ack(&mut self, delivery_tag: u64, multiple: bool) {
+    let confirmed = self.take_unacked(delivery_tag, multiple);
+    self.attach_pending_returns(&confirmed);
+    self.acked.extend(confirmed);
+  }
+
+  /// moves the delivery tags confirmed by a basic.nack out of `unacked` and into `nacked`
+  pub fn receive_nack(&mut self, delivery_tag: u64, multiple: bool) {
+    let confirmed = self.take_unacked(delivery_tag, multiple);
+    self.attach_pending_returns(&confirmed);
+    self.nacked.extend(confirmed);
+  }
+
+  /// marks confirmed delivery tags as returned if basic.return preceded the confirm
+  ///
+  /// the server sends each return right before the confirm of the same message, so with
+  /// a `multiple` confirm the pending returns belong to the last confirmed tags
+  fn attach_pending_returns(&mut self, confirmed: &BTreeSet<u64>) {
+    let returned: Vec<u64> = confirmed.iter().rev().take(self.pending_returns).cloned().collect();
+    self.pending_returns -= returned.len();
+    self.returned.extend(returned);
+  }
+
+  /// removes the confirmed delivery tags from `unacked`
+  ///
+  /// with `multiple`, every tag up to `delivery_tag` is confirmed, or all of them if it is 0
+  fn take_unacked(&mut self, delivery_tag: u64, multiple: bool) -> BTreeSet<u64> {
+    if multiple {
+      if delivery_tag == 0 {
+        self.unacked.split_off(&0)
+      } else {
+        let remaining = self.unacked.split_off(&(delivery_tag + 1));
+        mem::replace(&mut self.unacked, remaining)
+      }
+    } else {
+      let mut confirmed = BTreeSet::new();
+      if self.unacked.remove(&delivery_tag) {
+        confirmed.insert(delivery_tag);
+      } else {
+        warn!("channel {}: confirm for unknown delivery tag {}", self.id, delivery_tag);
+      }
+      confirmed
+    }
+  }
+
+  /// confirm mode is enabled, or confirm.select was sent
+  pub fn confirm_requested(&self) -> bool {
+    self.confirm || self.awaiting.iter().any(|answer| match *answer {
+      Answer::AwaitingConfirmSelectOk(_) => true,
+      _                                  => false,
+    })
+  }
+
+  /// transactions are enabled, or tx.select was sent
+  pub fn tx_requested(&self) -> bool {
+    self.tx || self.awaiting.iter().any(|answer| match *answer {
+      Answer::AwaitingTxSelectOk(_) => true,
+      _                             => false,
+    })
+  }
+
   pub fn is_connected(&self) -> bool {
     self.state != ChannelState::Initial && self.state != ChannelState::Closed && self.state != ChannelState::Error
   }
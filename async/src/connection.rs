@@ -1,12 +1,14 @@
-use std::{result,str};
+use std::{cmp,fmt,result,str};
 use std::default::Default;
+use std::error::Error as StdError;
 use std::io::{Error,ErrorKind,Result};
 use std::collections::{HashMap,VecDeque};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration,Instant};
 use nom::{IResult,Offset};
+use bytes::Bytes;
+use amq_protocol::protocol::AMQPHardError;
 use sasl;
-use sasl::client::Mechanism;
-use sasl::client::mechanisms::Plain;
 use cookie_factory::GenError;
 
 use format::frame::*;
@@ -14,6 +16,7 @@ use format::content::*;
 use channel::Channel;
 use message::*;
 use api::{Answer,ChannelState,RequestId};
+use auth::{self,SASLMechanism,SASLSession};
 use generated::*;
 use types::{AMQPValue,FieldTable};
 use error::{self, InvalidState};
@@ -60,6 +63,165 @@ pub struct Configuration {
   pub heartbeat:   u16,
 }
 
+/// properties sent to the server in `connection.start-ok`
+///
+/// they are displayed in the management UI of RabbitMQ
+#[derive(Clone,Debug,PartialEq)]
+pub struct ClientProperties {
+  /// name used to identify the connection, not sent if empty
+  pub connection_name: Option<String>,
+  pub product:         String,
+  pub version:         String,
+  pub platform:        String,
+  pub information:     String,
+  pub capabilities:    Capabilities,
+}
+
+impl Default for ClientProperties {
+  fn default() -> ClientProperties {
+    ClientProperties {
+      connection_name: None,
+      product:         "lapin".to_string(),
+      version:         env!("CARGO_PKG_VERSION").to_string(),
+      platform:        "rust".to_string(),
+      information:     env!("CARGO_PKG_REPOSITORY").to_string(),
+      capabilities:    Capabilities::default(),
+    }
+  }
+}
+
+impl ClientProperties {
+  /// field table sent as `client_properties`
+  pub fn to_field_table(&self) -> FieldTable {
+    let mut properties = FieldTable::new();
+    if let Some(ref name) = self.connection_name {
+      properties.insert("connection_name".to_string(), AMQPValue::LongString(name.clone()));
+    }
+    properties.insert("product".to_string(),      AMQPValue::LongString(self.product.clone()));
+    properties.insert("version".to_string(),      AMQPValue::LongString(self.version.clone()));
+    properties.insert("platform".to_string(),     AMQPValue::LongString(self.platform.clone()));
+    properties.insert("information".to_string(),  AMQPValue::LongString(self.information.clone()));
+    properties.insert("capabilities".to_string(), AMQPValue::FieldTable(self.capabilities.to_field_table()));
+    properties
+  }
+}
+
+/// protocol extensions advertised by the client
+///
+/// some notifications are only sent by RabbitMQ if the client declares it supports them
+#[derive(Clone,Debug,PartialEq)]
+pub struct Capabilities {
+  pub publisher_confirms:           bool,
+  pub consumer_cancel_notify:       bool,
+  pub exchange_exchange_bindings:   bool,
+  pub basic_nack:                   bool,
+  pub connection_blocked:           bool,
+  pub authentication_failure_close: bool,
+}
+
+impl Default for Capabilities {
+  fn default() -> Capabilities {
+    Capabilities {
+      publisher_confirms:           true,
+      consumer_cancel_notify:       true,
+      exchange_exchange_bindings:   true,
+      basic_nack:                   true,
+      connection_blocked:           true,
+      authentication_failure_close: true,
+    }
+  }
+}
+
+impl Capabilities {
+  pub fn to_field_table(&self) -> FieldTable {
+    let mut capabilities = FieldTable::new();
+    capabilities.insert("publisher_confirms".to_string(),           AMQPValue::Boolean(self.publisher_confirms));
+    capabilities.insert("consumer_cancel_notify".to_string(),       AMQPValue::Boolean(self.consumer_cancel_notify));
+    capabilities.insert("exchange_exchange_bindings".to_string(),   AMQPValue::Boolean(self.exchange_exchange_bindings));
+    capabilities.insert("basic.nack".to_string(),                   AMQPValue::Boolean(self.basic_nack));
+    capabilities.insert("connection.blocked".to_string(),           AMQPValue::Boolean(self.connection_blocked));
+    capabilities.insert("authentication_failure_close".to_string(), AMQPValue::Boolean(self.authentication_failure_close));
+    capabilities
+  }
+}
+
+/// properties sent by the server in `connection.start`
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct ServerProperties {
+  pub product:      Option<String>,
+  pub version:      Option<String>,
+  pub platform:     Option<String>,
+  pub cluster_name: Option<String>,
+  /// protocol extensions supported by the server, None if it did not send a capabilities table
+  pub capabilities: Option<HashMap<String, bool>>,
+  /// SASL mechanisms proposed by the server
+  pub mechanisms:   Vec<String>,
+  /// locales proposed by the server
+  pub locales:      Vec<String>,
+  /// the complete `server_properties` table
+  pub properties:   FieldTable,
+}
+
+impl ServerProperties {
+  pub fn from_start(start: &connection::Start) -> ServerProperties {
+    let string = |key: &str| match start.server_properties.get(key) {
+      Some(AMQPValue::LongString(value)) => Some(value.clone()),
+      _                                  => None,
+    };
+    let capabilities = match start.server_properties.get("capabilities") {
+      Some(AMQPValue::FieldTable(table)) => Some(table.iter().filter_map(|(name, value)| match *value {
+        AMQPValue::Boolean(supported) => Some((name.clone(), supported)),
+        _                             => None,
+      }).collect()),
+      _ => None,
+    };
+
+    ServerProperties {
+      product:      string("product"),
+      version:      string("version"),
+      platform:     string("platform"),
+      cluster_name: string("cluster_name"),
+      capabilities,
+      mechanisms:   start.mechanisms.split_whitespace().map(|s| s.to_string()).collect(),
+      locales:      start.locales.split_whitespace().map(|s| s.to_string()).collect(),
+      properties:   start.server_properties.clone(),
+    }
+  }
+
+  /// true if the server advertised the capability
+  pub fn has_capability(&self, name: &str) -> bool {
+    self.capabilities.as_ref().and_then(|capabilities| capabilities.get(name).cloned()).unwrap_or(false)
+  }
+}
+
+/// changes of the connection's state that the user might want to react to
+#[derive(Clone,Debug,PartialEq)]
+pub enum ConnectionEvent {
+  /// the server stopped accepting publishes, usually because of a memory or disk alarm
+  Blocked(String),
+  /// the server accepts publishes again
+  Unblocked,
+}
+
+/// reason sent by the server in a `connection.close` or `channel.close` method
+#[derive(Clone,Debug,PartialEq)]
+pub struct CloseReason {
+  pub reply_code: u16,
+  pub reply_text: String,
+  /// class of the method that caused the close, or 0
+  pub class_id:   u16,
+  /// method that caused the close, or 0
+  pub method_id:  u16,
+}
+
+impl fmt::Display for CloseReason {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "connection closed by server: {} {} (class {}, method {})", self.reply_code, self.reply_text, self.class_id, self.method_id)
+  }
+}
+
+impl StdError for CloseReason {}
+
 #[derive(Clone,Debug,PartialEq)]
 pub struct Credentials {
   username: String,
@@ -100,6 +262,34 @@ pub struct Connection {
   pub generated_names:   HashMap<RequestId, String>,
   /// credentials are stored in an option to remove them from memory once they are used
   pub credentials:       Option<Credentials>,
+  /// reason given by the server if it closed the connection
+  pub close_reason:      Option<CloseReason>,
+  /// serial of the last created channel
+  pub channel_serial:    u64,
+  /// serial and reason of the channels closed by the server, by channel id
+  pub closed_channels:   HashMap<u16, (u64, CloseReason)>,
+  /// SASL mechanisms the client accepts to use, by order of preference
+  pub auth_mechanisms:   Vec<SASLMechanism>,
+  /// SASL exchange in progress, only kept during the handshake
+  pub sasl_session:      Option<SASLSession>,
+  /// properties sent to the server during the handshake
+  pub client_properties: ClientProperties,
+  /// properties received from the server during the handshake
+  pub server_properties: Option<ServerProperties>,
+  /// locales the client accepts to use, by order of preference
+  pub locales:           Vec<String>,
+  /// reason sent by the server if it blocked the connection
+  pub blocked:           Option<String>,
+  /// list of events not yet consumed by `next_event`
+  pub events:            VecDeque<ConnectionEvent>,
+  /// last time `poll_timers` saw traffic coming from the server
+  pub last_read:         Option<Instant>,
+  /// last time `poll_timers` saw traffic going to the server
+  pub last_write:        Option<Instant>,
+  /// a frame was received since the last call to `poll_timers`
+  pub received_traffic:  bool,
+  /// a frame was sent since the last call to `poll_timers`
+  pub sent_traffic:      bool,
 }
 
 impl Connection {
@@ -125,6 +315,20 @@ impl Connection {
       finished_get_reqs: HashMap::new(),
       generated_names:   HashMap::new(),
       credentials:       None,
+      close_reason:      None,
+      channel_serial:    0,
+      closed_channels:   HashMap::new(),
+      auth_mechanisms:   vec![SASLMechanism::Plain, SASLMechanism::AMQPlain],
+      sasl_session:      None,
+      client_properties: ClientProperties::default(),
+      server_properties: None,
+      locales:           vec!["en_US".to_string()],
+      blocked:           None,
+      events:            VecDeque::new(),
+      last_read:         None,
+      last_write:        None,
+      received_traffic:  false,
+      sent_traffic:      false,
     }
   }
 
@@ -135,6 +339,26 @@ impl Connection {
     });
   }
 
+  /// sets the SASL mechanisms the client accepts to use, by order of preference
+  ///
+  /// the first one supported by the server will be used
+  pub fn set_auth_mechanisms(&mut self, mechanisms: &[SASLMechanism]) {
+    self.auth_mechanisms = mechanisms.to_vec();
+  }
+
+  pub fn set_locales(&mut self, locales: &[String]) {
+    self.locales = locales.to_vec();
+  }
+
+  pub fn set_client_properties(&mut self, properties: ClientProperties) {
+    self.client_properties = properties;
+  }
+
+  /// name shown for this connection in the management UI
+  pub fn set_connection_name(&mut self, name: &str) {
+    self.client_properties.connection_name = Some(name.to_string());
+  }
+
   pub fn set_vhost(&mut self, vhost: &str) {
     self.vhost = vhost.to_string();
   }
@@ -157,23 +381,123 @@ impl Connection {
   ///
   /// The channel will not be usable until `channel_open`
   /// is called with the channel id
-  pub fn create_channel(&mut self) -> Option<u16> {
+  ///
+  /// fails with `Error::ChannelLimitReached` if all the ids up to `channel_max` are in use
+  pub fn create_channel(&mut self) -> result::Result<u16, error::Error> {
     let _lock  = self.channel_id_lock.lock();
-    let offset = if self.channel_index == self.configuration.channel_max {
+    // 0 means the server does not limit the number of channels
+    let channel_max = match self.configuration.channel_max {
+      0   => u16::MAX,
+      max => max,
+    };
+    let offset = if self.channel_index >= channel_max {
       // skip 0 and go straight to 1
       1
     } else {
       self.channel_index + 1
     };
 
-    let id = (offset..self.configuration.channel_max).chain(1..offset).find(|id| {
-      self.channels.get(&id).map(|channel| !channel.is_connected()).unwrap_or(true)
-    })?;
+    // channels still opening or closing keep their id until they are removed
+    let id = (offset..=channel_max).chain(1..offset).find(|id| {
+      !self.channels.contains_key(id)
+    }).ok_or(error::Error::ChannelLimitReached(channel_max))?;
 
-    let c = Channel::new(id);
+    let mut c = Channel::new(id);
+    self.channel_serial += 1;
+    c.serial = self.channel_serial;
     self.channel_index = id;
     self.channels.insert(id, c);
-    Some(id)
+    Ok(id)
+  }
+
+  /// frees the state of a closed channel, its id can then be reused
+  pub fn remove_channel(&mut self, channel_id: u16) {
+    if channel_id != 0 && self.channels.remove(&channel_id).is_some() {
+      trace!("channel {} removed", channel_id);
+    }
+  }
+
+  /// true if the server does not accept publishes for now
+  pub fn is_blocked(&self) -> bool {
+    self.blocked.is_some()
+  }
+
+  /// next connection event, like the connection getting blocked or unblocked
+  ///
+  /// events are kept until they are consumed, so callers that do not need them should clear `events`
+  pub fn next_event(&mut self) -> Option<ConnectionEvent> {
+    self.events.pop_front()
+  }
+
+  /// updates the heartbeat timers, `now` being the current time
+  ///
+  /// this queues a heartbeat frame if nothing was sent for a heartbeat interval,
+  /// and fails with `Error::MissedHeartbeats` if nothing was received for two
+  /// intervals. It should be called at least at the instant returned by `next_timeout`
+  pub fn poll_timers(&mut self, now: Instant) -> result::Result<(), error::Error> {
+    let interval = match self.heartbeat_interval() {
+      Some(interval) => interval,
+      None           => return Ok(()),
+    };
+
+    if self.received_traffic || self.last_read.is_none() {
+      self.received_traffic = false;
+      self.last_read = Some(now);
+    }
+    if self.sent_traffic || self.last_write.is_none() {
+      self.sent_traffic = false;
+      self.last_write = Some(now);
+    }
+
+    let last_read = self.last_read.unwrap_or(now);
+    if now.duration_since(last_read) >= interval * 2 {
+      error!("missed two heartbeats from the server, last traffic was {:?} ago", now.duration_since(last_read));
+      self.state = ConnectionState::Error;
+      return Err(error::Error::MissedHeartbeats);
+    }
+
+    let last_write = self.last_write.unwrap_or(now);
+    if now.duration_since(last_write) >= interval {
+      debug!("queueing heartbeat");
+      self.frame_queue.push_back(Frame::Heartbeat(0));
+      self.last_write = Some(now);
+    }
+    Ok(())
+  }
+
+  /// when `poll_timers` should be called next
+  ///
+  /// returns `None` if heartbeats are disabled or if `poll_timers` was not
+  /// called since the connection was established
+  pub fn next_timeout(&self) -> Option<Instant> {
+    let interval = self.heartbeat_interval()?;
+    match (self.last_read, self.last_write) {
+      (Some(last_read), Some(last_write)) => Some(::std::cmp::min(last_read + interval * 2, last_write + interval)),
+      _                                   => None,
+    }
+  }
+
+  fn heartbeat_interval(&self) -> Option<Duration> {
+    match self.state {
+      ConnectionState::Connected | ConnectionState::Closing(_) if self.configuration.heartbeat != 0 => {
+        Some(Duration::from_secs(self.configuration.heartbeat.into()))
+      },
+      _ => None,
+    }
+  }
+
+  /// fails with `Error::MissingCapability` if the server said it does not support `capability`
+  ///
+  /// the check passes if the server properties are not known yet, or if the server
+  /// did not send a capabilities table at all
+  pub fn check_capability(&self, capability: &str) -> result::Result<(), error::Error> {
+    match self.server_properties {
+      Some(ref properties) if properties.capabilities.is_some() && !properties.has_capability(capability) => {
+        error!("the server does not support {}", capability);
+        Err(error::Error::MissingCapability(capability.to_string()))
+      },
+      _ => Ok(()),
+    }
   }
 
   pub fn set_channel_state(&mut self, channel_id: u16, new_state: ChannelState) {
@@ -209,6 +533,37 @@ impl Connection {
           .map(|c| c.state.clone())
   }
 
+  /// gets the serial of a channel, it changes when its id is reused
+  ///
+  /// returns None if the channel does not exist
+  pub fn get_serial(&self, channel_id: u16) -> Option<u64> {
+    self.channels
+          .get(&channel_id)
+          .map(|c| c.serial)
+  }
+
+  /// gets the reason sent by the server when it closed the channel
+  ///
+  /// returns None if the channel was not closed by the server, or if its id was reused since
+  pub fn get_close_reason(&self, channel_id: u16) -> Option<CloseReason> {
+    if self.channels.contains_key(&channel_id) {
+      return None;
+    }
+    self.closed_channels
+          .get(&channel_id)
+          .map(|closed| closed.1.clone())
+  }
+
+  /// gets the reason sent by the server when it closed the channel with this serial
+  ///
+  /// unlike `get_close_reason`, this still works after the id was reused
+  pub fn get_closed_channel_reason(&self, channel_id: u16, serial: u64) -> Option<CloseReason> {
+    self.closed_channels
+          .get(&channel_id)
+          .filter(|closed| closed.0 == serial)
+          .map(|closed| closed.1.clone())
+  }
+
   #[doc(hidden)]
   pub fn push_back_answer(&mut self, channel_id: u16, answer: Answer) {
     self.channels
@@ -265,6 +620,23 @@ impl Connection {
     self.channels.values().any(|channel| channel.queues.values().any(|queue| queue.consumers.values().any(|consumer| !consumer.messages.is_empty())))
   }
 
+  /// checks if the consumer is still registered on this channel and queue
+  ///
+  /// it is removed once cancelled, by the client or the server
+  pub fn has_consumer(&self, channel_id: u16, queue_name: &str, consumer_tag: &str) -> bool {
+    self.channels.get(&channel_id)
+      .and_then(|channel| channel.queues.get(queue_name))
+      .map(|queue| queue.consumers.contains_key(consumer_tag))
+      .unwrap_or(false)
+  }
+
+  /// checks if a consumer with this tag is active on the channel, through the consumer tag index
+  ///
+  /// a consumer cancelled by the server is not active anymore, even if some of its messages are still queued
+  pub fn has_consumer_tag(&self, channel_id: u16, consumer_tag: &str) -> bool {
+    self.channels.get(&channel_id).map(|channel| channel.consumer_index.contains_key(consumer_tag)).unwrap_or(false)
+  }
+
   /// gets the next message corresponding to a channel, queue and consumer tag
   ///
   /// if the channel id, queue and consumer tag have no link, the method
@@ -275,6 +647,19 @@ impl Connection {
       .and_then(|queue| queue.next_delivery(consumer_tag))
   }
 
+  /// checks if a message sent back by the server with basic.return is waiting on one of the channels
+  pub fn has_pending_returned_messages(&self) -> bool {
+    self.channels.values().any(|channel| !channel.returns.is_empty())
+  }
+
+  /// gets the next message the server sent back on this channel with basic.return
+  ///
+  /// if the channel does not exist or there is no message, the method will return None
+  pub fn next_returned_message(&mut self, channel_id: u16) -> Option<BasicReturnMessage> {
+    self.channels.get_mut(&channel_id)
+      .and_then(|channel| channel.returns.pop_front())
+  }
+
   /// gets the next message corresponding to a channel and queue, in response to a basic.get
   ///
   /// if the channel id and queue have no link, the method
@@ -302,11 +687,129 @@ impl Connection {
     Ok(self.state)
   }
 
+  /// starts closing the connection
+  ///
+  /// this queues a `connection.close` method. Frames received from the server
+  /// will be discarded until it answers with `connection.close-ok`, then the
+  /// connection will be in `ConnectionState::Closed`
+  pub fn close(&mut self, reply_code: u16, reply_text: &str) -> Result<ConnectionState> {
+    if self.state != ConnectionState::Connected {
+      return Err(Error::new(ErrorKind::Other, "invalid state"))
+    }
+
+    let close = Class::Connection(connection::Methods::Close(connection::Close {
+      reply_code: reply_code,
+      reply_text: reply_text.to_string(),
+      class_id:   0,
+      method_id:  0,
+    }));
+
+    debug!("client sending Connection::Close: {:?}", close);
+    self.frame_queue.push_back(Frame::Method(0, close));
+    self.state = ConnectionState::Closing(ClosingState::SentClose);
+    Ok(self.state)
+  }
+
+  /// replaces the secret used to authenticate, like an expiring OAuth2 token
+  ///
+  /// this queues RabbitMQ's `connection.update-secret`. The request is
+  /// finished once the server answers with `connection.update-secret-ok`
+  pub fn update_secret(&mut self, new_secret: &str, reason: &str) -> result::Result<RequestId, error::Error> {
+    if self.state != ConnectionState::Connected {
+      return Err(error::Error::NotConnected);
+    }
+
+    let update_secret = Class::Connection(connection::Methods::UpdateSecret(connection::UpdateSecret {
+      new_secret: new_secret.to_string(),
+      reason:     reason.to_string(),
+    }));
+
+    debug!("client sending Connection::UpdateSecret");
+    self.frame_queue.push_back(Frame::Method(0, update_secret));
+    let request_id = self.next_request_id();
+    if let Some(c) = self.channels.get_mut(&0) {
+      c.awaiting.push_back(Answer::AwaitingUpdateSecretOk(request_id));
+    }
+    Ok(request_id)
+  }
+
+  /// closes the connection because the server did not follow the protocol
+  ///
+  /// this queues a `connection.close` with the error's reply code, unless
+  /// the connection is not established or is already closing
+  pub fn close_on_error(&mut self, error: AMQPHardError, reply_text: &str) {
+    error!("closing the connection: {}", reply_text);
+    if self.state == ConnectionState::Connected {
+      let _ = self.close(error.get_id(), reply_text);
+    }
+  }
+
+  /// sends the connection.close matching a protocol violation of the server
+  ///
+  /// the connection goes to the error state if it was not established yet
+  pub fn close_on_protocol_error(&mut self, err: &error::Error) {
+    if let Some(hard_error) = err.hard_error() {
+      let name = match hard_error {
+        AMQPHardError::FRAMEERROR      => "FRAME_ERROR",
+        AMQPHardError::SYNTAXERROR     => "SYNTAX_ERROR",
+        AMQPHardError::UNEXPECTEDFRAME => "UNEXPECTED_FRAME",
+        _                              => "CONNECTION_ERROR",
+      };
+      self.close_on_error(hard_error, &format!("{} - {}", name, err));
+      if let ConnectionState::Closing(_) = self.state {} else {
+        self.state = ConnectionState::Error;
+      }
+    }
+  }
+
+  /// largest frame the server can send
+  ///
+  /// until frame_max is negotiated, it is the minimum frame size of the specification
+  pub fn inbound_frame_max(&self) -> u32 {
+    match self.state {
+      ConnectionState::Connected
+      | ConnectionState::Closing(_)
+      | ConnectionState::Connecting(ConnectingState::SentTuneOk)
+      | ConnectionState::Connecting(ConnectingState::SentOpen) if self.configuration.frame_max != 0 => {
+        self.configuration.frame_max
+      },
+      _ => FRAME_MIN_SIZE,
+    }
+  }
+
+  /// verifies the size announced by the frame header at the start of `data`
+  ///
+  /// a frame larger than the negotiated frame_max closes the connection with FRAME_ERROR
+  pub fn check_frame_size(&mut self, data: &[u8]) -> result::Result<(), error::Error> {
+    let frame_max = self.inbound_frame_max();
+    if let Some(size) = frame_size(data) {
+      if size > frame_max {
+        let err = error::Error::FrameTooLarge(size, frame_max);
+        self.close_on_protocol_error(&err);
+        return Err(err);
+      }
+    }
+    Ok(())
+  }
+
   /// next message to send to the network
   ///
   /// returns None if there's no message to send
   pub fn next_frame(&mut self) -> Option<Frame> {
-    self.frame_queue.pop_front()
+    let frame = self.frame_queue.pop_front();
+    if let Some(ref f) = frame {
+      self.frame_sent(f);
+    }
+    frame
+  }
+
+  /// updates the state once a frame left the frame queue
+  fn frame_sent(&mut self, frame: &Frame) {
+    self.sent_traffic = true;
+    if let &Frame::Method(0, Class::Connection(connection::Methods::CloseOk(_))) = frame {
+      debug!("sent Connection::CloseOk, connection is now closed");
+      self.state = ConnectionState::Closed;
+    }
   }
 
   /// writes the next message to a mutable byte slice
@@ -343,6 +846,7 @@ impl Connection {
 
     match gen_res {
       Ok(sz) => {
+        self.frame_sent(&next_msg);
         Ok((sz, self.state))
       },
       Err(e) => {
@@ -368,27 +872,51 @@ impl Connection {
   /// This method will update the state machine according to the ReceivedStart
   /// frame with `handle_frame`
   pub fn parse(&mut self, data: &[u8]) -> Result<(usize,ConnectionState)> {
-    let parsed_frame = frame(data);
-    match parsed_frame {
-      IResult::Done(_,_)     => {},
-      IResult::Incomplete(_) => {
-        return Ok((0,self.state));
+    let header = match frame_header(data) {
+      IResult::Done(_, header) => header,
+      IResult::Incomplete(_)   => return Ok((0,self.state)),
+      IResult::Error(_)        => {
+        if self.state == ConnectionState::Connecting(ConnectingState::SentProtocolHeader) {
+          // a server that does not support our version answers with its own protocol header
+          let err = match protocol_version(data) {
+            IResult::Incomplete(_)    => return Ok((0,self.state)),
+            IResult::Done(_, version) => error::Error::UnsupportedProtocolVersion(version),
+            IResult::Error(_)         => error::Error::NotAMQPServer,
+          };
+          error!("{}", err);
+          self.state = ConnectionState::Error;
+          return Err(Error::new(ErrorKind::InvalidData, err));
+        }
+
+        return Err(self.protocol_error(error::Error::UnknownFrameType(data[0])));
       },
-      IResult::Error(e) => {
-        //FIXME: should probably disconnect on error here
-        let err = format!("parse error: {:?}", e);
-        self.state = ConnectionState::Error;
-        return Err(Error::new(ErrorKind::Other, err))
-      }
+    };
+
+    // the size is checked before waiting for the rest of the frame
+    if let Err(e) = self.check_frame_size(data) {
+      return Err(Error::new(ErrorKind::InvalidData, e));
     }
 
-    let (i, f) = parsed_frame.unwrap();
+    let (remaining, payload) = match frame_payload(data, &header) {
+      IResult::Done(remaining, payload) => (remaining, payload),
+      IResult::Incomplete(_)            => return Ok((0,self.state)),
+      IResult::Error(_)                 => {
+        return Err(self.protocol_error(error::Error::InvalidFrameEnd(data[header.frame_size() - 1])));
+      },
+    };
+
+    let f = match parse_payload(payload, &header) {
+      IResult::Done(_, f) => f,
+      _                   => return Err(self.protocol_error(error::Error::InvalidFramePayload(header.channel_id))),
+    };
 
-    //FIXME: what happens if we fail to parse a packet in a channel?
-    // do we continue?
-    let consumed = data.offset(i);
+    let consumed = data.offset(remaining);
 
     if let Err(e) = self.handle_frame(f) {
+      if e.hard_error().is_some() {
+        // the connection.close was already queued by handle_frame
+        return Err(Error::new(ErrorKind::InvalidData, e));
+      }
       //FIXME: should probably disconnect on error here
       let err = format!("failed to handle frame: {:?}", e);
       self.state = ConnectionState::Error;
@@ -398,9 +926,28 @@ impl Connection {
     return Ok((consumed, self.state));
   }
 
+  /// closes the connection after a protocol violation, and wraps it for `parse`
+  fn protocol_error(&mut self, err: error::Error) -> Error {
+    self.close_on_protocol_error(&err);
+    Error::new(ErrorKind::InvalidData, err)
+  }
+
   /// updates the current state with a new received frame
+  ///
+  /// content frames are only accepted on a channel expecting them, otherwise
+  /// the connection is closed with UNEXPECTED_FRAME
   pub fn handle_frame(&mut self, f: Frame) -> result::Result<(), error::Error> {
     trace!("will handle frame: {:?}", f);
+    self.received_traffic = true;
+    if let ConnectionState::Closing(_) = self.state {
+      match f {
+        Frame::Method(0, _) => {},
+        f => {
+          trace!("connection is closing, discarding frame: {:?}", f);
+          return Ok(());
+        },
+      }
+    }
     match f {
       Frame::ProtocolHeader => {
         error!("error: the client should not receive a protocol header");
@@ -408,7 +955,7 @@ impl Connection {
       },
       Frame::Method(channel_id, method) => {
         if channel_id == 0 {
-          self.handle_global_method(method);
+          self.handle_global_method(method)?;
         } else {
           self.receive_method(channel_id, method)?;
         }
@@ -417,17 +964,41 @@ impl Connection {
         debug!("received heartbeat from server");
       },
       Frame::Header(channel_id, _, header) => {
+        self.check_content_frame(channel_id, "content header", |state| match *state {
+          ChannelState::WillReceiveContent(..) | ChannelState::WillReceiveReturn => true,
+          _                                                                     => false,
+        })?;
         self.handle_content_header_frame(channel_id, header.body_size, header.properties);
       },
       Frame::Body(channel_id, payload) => {
+        self.check_content_frame(channel_id, "content body", |state| match *state {
+          ChannelState::ReceivingContent(..) | ChannelState::ReceivingReturn(_) => true,
+          _                                                                     => false,
+        })?;
         self.handle_body_frame(channel_id, payload);
       }
     };
     Ok(())
   }
 
+  /// verifies that a content frame is received on a channel in the state accepting it
+  fn check_content_frame<F: Fn(&ChannelState) -> bool>(&mut self, channel_id: u16, kind: &str, expected: F) -> result::Result<(), error::Error> {
+    let err = if channel_id == 0 {
+      format!("{} on channel 0", kind)
+    } else {
+      match self.channels.get(&channel_id).map(|channel| &channel.state) {
+        Some(state) if expected(state) => return Ok(()),
+        Some(state)                    => format!("{} on channel {} in state {:?}", kind, channel_id, state),
+        None                           => format!("{} on unknown channel {}", kind, channel_id),
+      }
+    };
+    let err = error::Error::UnexpectedFrame(err);
+    self.close_on_protocol_error(&err);
+    Err(err)
+  }
+
   #[doc(hidden)]
-  pub fn handle_global_method(&mut self, c: Class) {
+  pub fn handle_global_method(&mut self, c: Class) -> result::Result<(), error::Error> {
     match self.state {
       ConnectionState::Initial | ConnectionState::Closed | ConnectionState::Error => {
         self.state = ConnectionState::Error
@@ -441,9 +1012,25 @@ impl Connection {
             if let Class::Connection(connection::Methods::Start(s)) = c {
               trace!("Server sent Connection::Start: {:?}", s);
               self.state = ConnectionState::Connecting(ConnectingState::ReceivedStart);
-
-              let mut h = FieldTable::new();
-              h.insert("product".to_string(), AMQPValue::LongString("lapin".to_string()));
+              self.server_properties = Some(ServerProperties::from_start(&s));
+
+              let mechanism = match auth::select_mechanism(&self.auth_mechanisms, &s.mechanisms) {
+                Some(mechanism) => mechanism,
+                None => {
+                  error!("server does not support any of the SASL mechanisms {:?}, it offered: {}", self.auth_mechanisms, s.mechanisms);
+                  self.state = ConnectionState::Error;
+                  return Err(error::Error::NoSupportedSASLMechanism(s.mechanisms));
+                }
+              };
+
+              let locale = match self.locales.iter().find(|locale| s.locales.split_whitespace().any(|l| l == locale.as_str())) {
+                Some(locale) => locale.clone(),
+                None => {
+                  error!("server does not support any of the locales {:?}, it offered: {}", self.locales, s.locales);
+                  self.state = ConnectionState::Error;
+                  return Err(error::Error::NoSupportedLocale(s.locales));
+                }
+              };
 
               let saved_creds = self.credentials.take().unwrap_or(Credentials::default());
 
@@ -451,19 +1038,27 @@ impl Connection {
                 .with_username(saved_creds.username)
                 .with_password(saved_creds.password);
 
-              let mut mechanism = Plain::from_credentials(creds).unwrap();
-
-              let initial_data = mechanism.initial().unwrap();
-              let s = str::from_utf8(&initial_data).unwrap();
+              let session = SASLSession::new(&mechanism, creds);
+              let response = session.and_then(|session| {
+                let response = session.initial();
+                self.sasl_session = Some(session);
+                response
+              });
+              let response = match response {
+                Ok(response) => response,
+                Err(e) => {
+                  error!("could not generate the {} response: {}", mechanism.name(), e);
+                  self.state = ConnectionState::Error;
+                  return Err(error::Error::SASLError(e));
+                }
+              };
 
-              //FIXME: fill with user configured data
-              //we need to handle the server properties, and have some client properties
               let start_ok = Class::Connection(connection::Methods::StartOk(
                 connection::StartOk {
-                  client_properties: h,
-                  mechanism: "PLAIN".to_string(),
-                  locale:    "en_US".to_string(), // FIXME: comes from the server
-                  response:  s.to_string(),
+                  client_properties: self.client_properties.to_field_table(),
+                  mechanism: mechanism.name().to_string(),
+                  locale,
+                  response:  response,
                 }
               ));
 
@@ -478,81 +1073,27 @@ impl Connection {
           /*ConnectingState::ReceivedStart => {
             trace!("state {:?}\treceived\t{:?}", self.state, c);
           },*/
-          ConnectingState::SentStartOk => {
-            if let Class::Connection(connection::Methods::Tune(t)) = c {
-              debug!("Server sent Connection::Tune: {:?}", t);
-              self.state = ConnectionState::Connecting(ConnectingState::ReceivedTune);
-
-              if self.configuration.heartbeat == 0 {
-                // If we disable the heartbeat but the server don't, follow him and enable it too
-                self.configuration.heartbeat = t.heartbeat;
-              } else if t.heartbeat != 0 && t.heartbeat < self.configuration.heartbeat {
-                // If both us and the server want heartbeat enabled, pick the lowest value.
-                self.configuration.heartbeat = t.heartbeat;
-              }
-
-              if t.channel_max != 0 {
-                if self.configuration.channel_max == 0 {
-                  // 0 means we want to take the server's value
-                  self.configuration.channel_max = t.channel_max;
-                } else if t.channel_max < self.configuration.channel_max {
-                  // If both us and the server specified a channel_max, pick the lowest value.
-                  self.configuration.channel_max = t.channel_max;
-                }
-              }
-              if self.configuration.channel_max == 0 {
-                  self.configuration.channel_max = u16::max_value();
-              }
-
-              if t.frame_max != 0 {
-                if self.configuration.frame_max == 0 {
-                  // 0 means we want to take the server's value
-                  self.configuration.frame_max = t.frame_max;
-                } else if t.frame_max < self.configuration.frame_max {
-                  // If both us and the server specified a frame_max, pick the lowest value.
-                  self.configuration.frame_max = t.frame_max;
-                }
-              }
-              if self.configuration.frame_max == 0 {
-                  self.configuration.frame_max = u32::max_value();
-              }
-
-              let tune_ok = Class::Connection(connection::Methods::TuneOk(
-                connection::TuneOk {
-                  channel_max : self.configuration.channel_max,
-                  frame_max   : self.configuration.frame_max,
-                  heartbeat   : self.configuration.heartbeat,
-                }
-              ));
-
-              debug!("client sending Connection::TuneOk: {:?}", tune_ok);
-
-              self.frame_queue.push_back(Frame::Method(0, tune_ok));
-              self.state = ConnectionState::Connecting(ConnectingState::SentTuneOk);
-
-              let open = Class::Connection(connection::Methods::Open(
-                  connection::Open {
-                    virtual_host: self.vhost.clone(),
-                    capabilities: "".to_string(),
-                    insist:       false,
-                  }
-                  ));
-
-              debug!("client sending Connection::Open: {:?}", open);
-              self.frame_queue.push_back(Frame::Method(0,open));
-              self.state = ConnectionState::Connecting(ConnectingState::SentOpen);
-
-            } else {
-              trace!("waiting for class Connection method Start, got {:?}", c);
-              self.state = ConnectionState::Error;
+          ConnectingState::SentStartOk | ConnectingState::SentSecure => {
+            match c {
+              Class::Connection(connection::Methods::Tune(t)) => {
+                self.receive_connection_tune(t);
+              },
+              Class::Connection(connection::Methods::Secure(secure)) => {
+                self.receive_connection_secure(secure)?;
+              },
+              // with authentication_failure_close, the server explains why it refused the credentials
+              Class::Connection(connection::Methods::Close(close)) => {
+                self.receive_connection_close(close);
+              },
+              c => {
+                trace!("waiting for class Connection method Tune or Secure, got {:?}", c);
+                self.state = ConnectionState::Error;
+              },
             }
           },
           ConnectingState::ReceivedSecure => {
             trace!("state {:?}\treceived\t{:?}", self.state, c);
           },
-          ConnectingState::SentSecure => {
-            trace!("state {:?}\treceived\t{:?}", self.state, c);
-          },
           ConnectingState::ReceivedSecondSecure => {
             trace!("state {:?}\treceived\t{:?}", self.state, c);
           },
@@ -561,12 +1102,19 @@ impl Connection {
           },
           ConnectingState::SentOpen => {
             trace!("state {:?}\treceived\t{:?}", self.state, c);
-            if let Class::Connection(connection::Methods::OpenOk(o)) = c {
-              debug!("Server sent Connection::OpenOk: {:?}, client now connected", o);
-              self.state = ConnectionState::Connected;
-            } else {
-              trace!("waiting for class Connection method Start, got {:?}", c);
-              self.state = ConnectionState::Error;
+            match c {
+              Class::Connection(connection::Methods::OpenOk(o)) => {
+                debug!("Server sent Connection::OpenOk: {:?}, client now connected", o);
+                self.state = ConnectionState::Connected;
+              },
+              // the vhost does not exist or the user cannot access it
+              Class::Connection(connection::Methods::Close(close)) => {
+                self.receive_connection_close(close);
+              },
+              c => {
+                trace!("waiting for class Connection method OpenOk, got {:?}", c);
+                self.state = ConnectionState::Error;
+              },
             }
           },
           ConnectingState::Error => {
@@ -578,52 +1126,240 @@ impl Connection {
           }
         }
       },
-      ConnectionState::Connected => {},
-      ConnectionState::Closing(_) => {},
+      ConnectionState::Connected => {
+        match c {
+          Class::Connection(connection::Methods::Close(close)) => {
+            self.receive_connection_close(close);
+          },
+          Class::Connection(connection::Methods::Blocked(blocked)) => {
+            warn!("Server blocked the connection: {}", blocked.reason);
+            self.blocked = Some(blocked.reason.clone());
+            self.events.push_back(ConnectionEvent::Blocked(blocked.reason));
+          },
+          Class::Connection(connection::Methods::Unblocked(_)) => {
+            info!("Server unblocked the connection");
+            self.blocked = None;
+            self.events.push_back(ConnectionEvent::Unblocked);
+          },
+          Class::Connection(connection::Methods::UpdateSecretOk(_)) => {
+            match self.get_next_answer(0) {
+              Some(Answer::AwaitingUpdateSecretOk(request_id)) => {
+                debug!("Server accepted the new secret");
+                self.finished_reqs.insert(request_id, true);
+              },
+              answer => {
+                error!("unexpected Connection::UpdateSecretOk, was waiting for {:?}", answer);
+                return Err(error::Error::UnexpectedAnswer);
+              },
+            }
+          },
+          c => {
+            trace!("state {:?}\treceived\t{:?}", self.state, c);
+          },
+        }
+      },
+      ConnectionState::Closing(closing_state) => {
+        match (closing_state, c) {
+          (ClosingState::SentClose, Class::Connection(connection::Methods::CloseOk(_))) => {
+            debug!("Server sent Connection::CloseOk, connection is now closed");
+            self.state = ConnectionState::Closing(ClosingState::ReceivedCloseOk);
+            for channel in self.channels.values_mut().filter(|c| c.id != 0) {
+              channel.state = ChannelState::Closed;
+            }
+            self.state = ConnectionState::Closed;
+          },
+          (ClosingState::SentClose, Class::Connection(connection::Methods::Close(close))) => {
+            // both sides sent connection.close at the same time
+            self.receive_connection_close(close);
+          },
+          (_, c) => {
+            trace!("connection is closing, discarding method: {:?}", c);
+          },
+        }
+      },
     };
+    Ok(())
   }
 
-  #[doc(hidden)]
-  pub fn handle_content_header_frame(&mut self, channel_id: u16, size: u64, properties: basic::Properties) {
-    let state = self.channels.get_mut(&channel_id).map(|channel| {
-      channel.state.clone()
-    }).unwrap();
-    if let ChannelState::WillReceiveContent(queue_name, consumer_tag) = state {
-      if size > 0 {
-        self.set_channel_state(channel_id, ChannelState::ReceivingContent(queue_name.clone(), consumer_tag.clone(), size as usize));
-      } else {
-        self.set_channel_state(channel_id, ChannelState::Connected);
+  fn receive_connection_secure(&mut self, secure: connection::Secure) -> result::Result<(), error::Error> {
+    debug!("Server sent Connection::Secure: {:?}", secure);
+    self.state = if self.state == ConnectionState::Connecting(ConnectingState::SentStartOk) {
+      ConnectionState::Connecting(ConnectingState::ReceivedSecure)
+    } else {
+      ConnectionState::Connecting(ConnectingState::ReceivedSecondSecure)
+    };
+
+    let response = match self.sasl_session {
+      Some(ref session) => session.response(&secure.challenge),
+      None              => Err("no SASL exchange in progress".to_string()),
+    };
+    let response = match response {
+      Ok(response) => response,
+      Err(e) => {
+        error!("could not answer the SASL challenge: {}", e);
+        self.state = ConnectionState::Error;
+        return Err(error::Error::SASLError(e));
       }
-      if let Some(ref mut c) = self.channels.get_mut(&channel_id) {
-        if let Some(ref mut q) = c.queues.get_mut(&queue_name) {
-          if let Some(ref consumer_tag) = consumer_tag {
-            if let Some(ref mut cs) = q.consumers.get_mut(consumer_tag) {
-              if let Some(msg) = cs.current_message.as_mut() {
-                msg.properties = properties;
-              }
-              if size == 0 {
-                let message = cs.current_message.take().expect("there should be an in flight message in the consumer");
-                cs.messages.push_back(message);
-              }
-            }
-          } else {
-            if let Some(msg) = q.current_get_message.as_mut() {
-              msg.delivery.properties = properties;
-            }
-            if size == 0 {
+    };
+
+    let secure_ok = Class::Connection(connection::Methods::SecureOk(
+      connection::SecureOk {
+        response,
+      }
+    ));
+
+    debug!("client sending Connection::SecureOk");
+    self.frame_queue.push_back(Frame::Method(0, secure_ok));
+    self.state = ConnectionState::Connecting(ConnectingState::SentSecure);
+    Ok(())
+  }
+
+  fn receive_connection_tune(&mut self, t: connection::Tune) {
+    debug!("Server sent Connection::Tune: {:?}", t);
+    // authentication is over
+    self.sasl_session = None;
+    self.state = ConnectionState::Connecting(ConnectingState::ReceivedTune);
+
+    if self.configuration.heartbeat == 0 {
+      // If we disable the heartbeat but the server don't, follow him and enable it too
+      self.configuration.heartbeat = t.heartbeat;
+    } else if t.heartbeat != 0 && t.heartbeat < self.configuration.heartbeat {
+      // If both us and the server want heartbeat enabled, pick the lowest value.
+      self.configuration.heartbeat = t.heartbeat;
+    }
+
+    if t.channel_max != 0 {
+      if self.configuration.channel_max == 0 {
+        // 0 means we want to take the server's value
+        self.configuration.channel_max = t.channel_max;
+      } else if t.channel_max < self.configuration.channel_max {
+        // If both us and the server specified a channel_max, pick the lowest value.
+        self.configuration.channel_max = t.channel_max;
+      }
+    }
+    if self.configuration.channel_max == 0 {
+        self.configuration.channel_max = u16::max_value();
+    }
+
+    if t.frame_max != 0 {
+      if self.configuration.frame_max == 0 {
+        // 0 means we want to take the server's value
+        self.configuration.frame_max = t.frame_max;
+      } else if t.frame_max < self.configuration.frame_max {
+        // If both us and the server specified a frame_max, pick the lowest value.
+        self.configuration.frame_max = t.frame_max;
+      }
+    }
+    if self.configuration.frame_max == 0 {
+        self.configuration.frame_max = u32::max_value();
+    }
+
+    let tune_ok = Class::Connection(connection::Methods::TuneOk(
+      connection::TuneOk {
+        channel_max : self.configuration.channel_max,
+        frame_max   : self.configuration.frame_max,
+        heartbeat   : self.configuration.heartbeat,
+      }
+    ));
+
+    debug!("client sending Connection::TuneOk: {:?}", tune_ok);
+
+    self.frame_queue.push_back(Frame::Method(0, tune_ok));
+    self.state = ConnectionState::Connecting(ConnectingState::SentTuneOk);
+
+    let open = Class::Connection(connection::Methods::Open(
+        connection::Open {
+          virtual_host: self.vhost.clone(),
+          capabilities: "".to_string(),
+          insist:       false,
+        }
+        ));
+
+    debug!("client sending Connection::Open: {:?}", open);
+    self.frame_queue.push_back(Frame::Method(0,open));
+    self.state = ConnectionState::Connecting(ConnectingState::SentOpen);
+  }
+
+  /// handles a `connection.close` sent by the server
+  ///
+  /// the reason is stored in `close_reason`, every channel is marked as closed
+  /// and a `connection.close-ok` is queued. The connection will be in
+  /// `ConnectionState::Closed` once that frame is sent
+  fn receive_connection_close(&mut self, close: connection::Close) {
+    error!("Server sent Connection::Close: {:?}", close);
+    self.state = ConnectionState::Closing(ClosingState::ReceivedClose);
+    self.close_reason = Some(CloseReason {
+      reply_code: close.reply_code,
+      reply_text: close.reply_text,
+      class_id:   close.class_id,
+      method_id:  close.method_id,
+    });
+
+    for channel in self.channels.values_mut().filter(|c| c.id != 0) {
+      channel.state = ChannelState::Closed;
+    }
+
+    let close_ok = Class::Connection(connection::Methods::CloseOk(connection::CloseOk {}));
+    debug!("client sending Connection::CloseOk: {:?}", close_ok);
+    self.frame_queue.push_back(Frame::Method(0, close_ok));
+    self.state = ConnectionState::Closing(ClosingState::SentCloseOk);
+  }
+
+  #[doc(hidden)]
+  pub fn handle_content_header_frame(&mut self, channel_id: u16, size: u64, properties: basic::Properties) {
+    let state = self.channels.get_mut(&channel_id).map(|channel| {
+      channel.state.clone()
+    }).unwrap();
+    if let ChannelState::WillReceiveContent(queue_name, consumer_tag) = state {
+      if size > 0 {
+        self.set_channel_state(channel_id, ChannelState::ReceivingContent(queue_name.clone(), consumer_tag.clone(), size as usize));
+      } else {
+        self.set_channel_state(channel_id, ChannelState::Connected);
+      }
+      if let Some(ref mut c) = self.channels.get_mut(&channel_id) {
+        if let Some(ref mut q) = c.queues.get_mut(&queue_name) {
+          if let Some(ref consumer_tag) = consumer_tag {
+            if let Some(ref mut cs) = q.consumers.get_mut(consumer_tag) {
+              if let Some(msg) = cs.current_message.as_mut() {
+                msg.properties = properties;
+              }
+              if size == 0 {
+                let message = cs.current_message.take().expect("there should be an in flight message in the consumer");
+                cs.messages.push_back(message);
+              }
+            }
+          } else {
+            if let Some(msg) = q.current_get_message.as_mut() {
+              msg.delivery.properties = properties;
+            }
+            if size == 0 {
               let message = q.current_get_message.take().expect("there should be an in flight message in the queue");
               q.get_messages.push_back(message);
             }
           }
         }
       }
+    } else if let ChannelState::WillReceiveReturn = state {
+      if size > 0 {
+        self.set_channel_state(channel_id, ChannelState::ReceivingReturn(size as usize));
+      } else {
+        self.set_channel_state(channel_id, ChannelState::Connected);
+      }
+      if let Some(ref mut c) = self.channels.get_mut(&channel_id) {
+        if let Some(msg) = c.current_return.as_mut() {
+          msg.delivery.properties = properties;
+        }
+        if size == 0 {
+          c.complete_returned_message();
+        }
+      }
     } else {
       self.set_channel_state(channel_id, ChannelState::Error);
     }
   }
 
   #[doc(hidden)]
-  pub fn handle_body_frame(&mut self, channel_id: u16, payload: Vec<u8>) {
+  pub fn handle_body_frame(&mut self, channel_id: u16, payload: Bytes) {
     let state = self.channels.get_mut(&channel_id).map(|channel| {
       channel.state.clone()
     }).unwrap();
@@ -661,6 +1397,26 @@ impl Connection {
         error!("body frame too large");
         self.set_channel_state(channel_id, ChannelState::Error);
       }
+    } else if let ChannelState::ReceivingReturn(remaining_size) = state {
+      if remaining_size >= payload_size {
+        if let Some(ref mut c) = self.channels.get_mut(&channel_id) {
+          if let Some(msg) = c.current_return.as_mut() {
+            msg.delivery.receive_content(payload);
+          }
+          if remaining_size == payload_size {
+            c.complete_returned_message();
+          }
+        }
+
+        if remaining_size == payload_size {
+          self.set_channel_state(channel_id, ChannelState::Connected);
+        } else {
+          self.set_channel_state(channel_id, ChannelState::ReceivingReturn(remaining_size - payload_size));
+        }
+      } else {
+        error!("body frame too large");
+        self.set_channel_state(channel_id, ChannelState::Error);
+      }
     } else {
       self.set_channel_state(channel_id, ChannelState::Error);
     }
@@ -669,19 +1425,24 @@ impl Connection {
   /// generates the content header and content frames for a payload
   ///
   /// the frames will be stored in the frame queue until they're written
-  /// to the network.
-  pub fn send_content_frames(&mut self, channel_id: u16, class_id: u16, slice: &[u8], properties: basic::Properties) {
+  /// to the network. The body frames are slices of the payload, it is not copied.
+  pub fn send_content_frames<P: Into<Bytes>>(&mut self, channel_id: u16, class_id: u16, payload: P, properties: basic::Properties) {
+    let payload = payload.into();
     let header = ContentHeader {
       class_id:       class_id,
       weight:         0,
-      body_size:      slice.len() as u64,
+      body_size:      payload.len() as u64,
       properties:     properties,
     };
     self.frame_queue.push_back(Frame::Header(channel_id, class_id, header));
 
     //a content body frame 8 bytes of overhead
-    for chunk in slice.chunks(self.configuration.frame_max as usize - 8) {
-      self.frame_queue.push_back(Frame::Body(channel_id, Vec::from(chunk)));
+    let chunk_size = self.configuration.frame_max as usize - 8;
+    let mut start = 0;
+    while start < payload.len() {
+      let end = cmp::min(start + chunk_size, payload.len());
+      self.frame_queue.push_back(Frame::Body(channel_id, payload.slice(start, end)));
+      start = end;
     }
   }
 
@@ -697,6 +1458,17 @@ mod tests {
     extern crate env_logger;
 
     use super::*;
+    use amq_protocol::protocol::constants;
+
+    /// connection in the connected state with one open channel
+    fn connected_channel() -> (Connection, u16) {
+        let mut conn = Connection::new();
+        conn.state = ConnectionState::Connected;
+        conn.configuration.channel_max = 2047;
+        let channel_id = conn.create_channel().unwrap();
+        conn.set_channel_state(channel_id, ChannelState::Connected);
+        (conn, channel_id)
+    }
 
     #[test]
     fn basic_consume_small_payload() {
@@ -705,11 +1477,7 @@ mod tests {
         use queue::{Consumer, Queue};
 
         // Bootstrap connection state to a consuming state
-        let mut conn = Connection::new();
-        conn.state = ConnectionState::Connected;
-        conn.configuration.channel_max = 2047;
-        let channel_id = conn.create_channel().unwrap();
-        conn.set_channel_state(channel_id, ChannelState::Connected);
+        let (mut conn, channel_id) = connected_channel();
         let queue_name = "consumed".to_string();
         let mut queue = Queue::new(queue_name.clone(), 0, 0);
         let consumer_tag = "consumer-tag".to_string();
@@ -721,10 +1489,12 @@ mod tests {
             nowait: false,
             current_message: None,
             messages: VecDeque::new(),
+            cancelled: false,
         };
         queue.consumers.insert(consumer_tag.clone(), consumer);
         conn.channels.get_mut(&channel_id).map(|c| {
             c.queues.insert(queue_name.clone(), queue);
+            c.consumer_index.insert(consumer_tag.clone(), queue_name.clone());
         });
         // Now test the state machine behaviour
         {
@@ -771,7 +1541,7 @@ mod tests {
             assert_eq!(channel_state, expected_state);
         }
         {
-           let body_frame = Frame::Body(channel_id, "{}".as_bytes().to_vec());
+           let body_frame = Frame::Body(channel_id, Bytes::from_static(b"{}"));
            conn.handle_frame(body_frame).unwrap();
             let channel_state = conn.channels.get_mut(&channel_id)
                 .map(|channel| channel.state.clone())
@@ -788,11 +1558,7 @@ mod tests {
         use queue::{Consumer, Queue};
 
         // Bootstrap connection state to a consuming state
-        let mut conn = Connection::new();
-        conn.state = ConnectionState::Connected;
-        conn.configuration.channel_max = 2047;
-        let channel_id = conn.create_channel().unwrap();
-        conn.set_channel_state(channel_id, ChannelState::Connected);
+        let (mut conn, channel_id) = connected_channel();
         let queue_name = "consumed".to_string();
         let mut queue = Queue::new(queue_name.clone(), 0, 0);
         let consumer_tag = "consumer-tag".to_string();
@@ -804,10 +1570,12 @@ mod tests {
             nowait: false,
             current_message: None,
             messages: VecDeque::new(),
+            cancelled: false,
         };
         queue.consumers.insert(consumer_tag.clone(), consumer);
         conn.channels.get_mut(&channel_id).map(|c| {
             c.queues.insert(queue_name.clone(), queue);
+            c.consumer_index.insert(consumer_tag.clone(), queue_name.clone());
         });
         // Now test the state machine behaviour
         {
@@ -854,4 +1622,772 @@ mod tests {
             assert_eq!(channel_state, expected_state);
         }
     }
+
+    #[test]
+    fn server_connection_close() {
+        let _ = env_logger::try_init();
+
+        let (mut conn, channel_id) = connected_channel();
+
+        let close_frame = Frame::Method(0, Class::Connection(connection::Methods::Close(connection::Close {
+            reply_code: 320,
+            reply_text: "CONNECTION_FORCED - broker forced connection closure".to_string(),
+            class_id:   0,
+            method_id:  0,
+        })));
+        conn.handle_frame(close_frame).unwrap();
+        assert_eq!(conn.state, ConnectionState::Closing(ClosingState::SentCloseOk));
+        assert_eq!(conn.get_state(channel_id), Some(ChannelState::Closed));
+        assert_eq!(conn.close_reason, Some(CloseReason {
+            reply_code: 320,
+            reply_text: "CONNECTION_FORCED - broker forced connection closure".to_string(),
+            class_id:   0,
+            method_id:  0,
+        }));
+
+        let close_ok = Frame::Method(0, Class::Connection(connection::Methods::CloseOk(connection::CloseOk {})));
+        assert_eq!(conn.next_frame(), Some(close_ok));
+        assert_eq!(conn.state, ConnectionState::Closed);
+    }
+
+    #[test]
+    fn connection_close_during_handshake() {
+        let _ = env_logger::try_init();
+
+        let refused = || connection::Close {
+            reply_code: 403,
+            reply_text: "ACCESS_REFUSED - Login was refused using authentication mechanism PLAIN".to_string(),
+            class_id:   0,
+            method_id:  0,
+        };
+
+        for state in &[ConnectingState::SentStartOk, ConnectingState::SentSecure, ConnectingState::SentOpen] {
+            let mut conn = Connection::new();
+            conn.state = ConnectionState::Connecting(*state);
+
+            conn.handle_frame(Frame::Method(0, Class::Connection(connection::Methods::Close(refused())))).unwrap();
+            assert_eq!(conn.state, ConnectionState::Closing(ClosingState::SentCloseOk));
+            assert_eq!(conn.close_reason.as_ref().map(|reason| reason.reply_code), Some(403));
+
+            let close_ok = Frame::Method(0, Class::Connection(connection::Methods::CloseOk(connection::CloseOk {})));
+            assert_eq!(conn.next_frame(), Some(close_ok));
+            assert_eq!(conn.state, ConnectionState::Closed);
+        }
+    }
+
+    #[test]
+    fn client_connection_close() {
+        let _ = env_logger::try_init();
+
+        let (mut conn, channel_id) = connected_channel();
+
+        assert_eq!(conn.close(200, "OK").unwrap(), ConnectionState::Closing(ClosingState::SentClose));
+        let close = Frame::Method(0, Class::Connection(connection::Methods::Close(connection::Close {
+            reply_code: 200,
+            reply_text: "OK".to_string(),
+            class_id:   0,
+            method_id:  0,
+        })));
+        assert_eq!(conn.next_frame(), Some(close));
+
+        // frames received while closing are discarded
+        conn.handle_frame(Frame::Body(channel_id, Bytes::from_static(b"ignored"))).unwrap();
+        assert_eq!(conn.get_state(channel_id), Some(ChannelState::Connected));
+
+        let close_ok_frame = Frame::Method(0, Class::Connection(connection::Methods::CloseOk(connection::CloseOk {})));
+        conn.handle_frame(close_ok_frame).unwrap();
+        assert_eq!(conn.state, ConnectionState::Closed);
+        assert_eq!(conn.get_state(channel_id), Some(ChannelState::Closed));
+        assert_eq!(conn.close_reason, None);
+    }
+
+    #[test]
+    fn missing_server_capability() {
+        let _ = env_logger::try_init();
+
+        let (mut conn, channel_id) = connected_channel();
+
+        let mut capabilities = FieldTable::new();
+        capabilities.insert("publisher_confirms".to_string(), AMQPValue::Boolean(false));
+        capabilities.insert("basic.nack".to_string(), AMQPValue::Boolean(true));
+        let mut server_properties = FieldTable::new();
+        server_properties.insert("product".to_string(), AMQPValue::LongString("RabbitMQ".to_string()));
+        server_properties.insert("capabilities".to_string(), AMQPValue::FieldTable(capabilities));
+        let properties = ServerProperties::from_start(&connection::Start {
+            version_major:     0,
+            version_minor:     9,
+            server_properties,
+            mechanisms:        "PLAIN AMQPLAIN".to_string(),
+            locales:           "en_US".to_string(),
+        });
+        assert_eq!(properties.product, Some("RabbitMQ".to_string()));
+        assert_eq!(properties.mechanisms, vec!["PLAIN".to_string(), "AMQPLAIN".to_string()]);
+        conn.server_properties = Some(properties);
+
+        let queued = conn.frame_queue.len();
+        assert_eq!(conn.confirm_select(channel_id, false), Err(error::Error::MissingCapability("publisher_confirms".to_string())));
+        assert_eq!(conn.frame_queue.len(), queued);
+        assert_eq!(conn.basic_nack(channel_id, 1, false, true), Ok(()));
+        assert_eq!(conn.frame_queue.len(), queued + 1);
+
+        // without a capabilities table, nothing is known about the server
+        conn.server_properties = Some(ServerProperties::from_start(&connection::Start {
+            version_major:     0,
+            version_minor:     9,
+            server_properties: FieldTable::new(),
+            mechanisms:        "PLAIN".to_string(),
+            locales:           "en_US".to_string(),
+        }));
+        assert_eq!(conn.check_capability("publisher_confirms"), Ok(()));
+    }
+
+    #[test]
+    fn locale_negotiation() {
+        let _ = env_logger::try_init();
+
+        let start = |locales: &str| Frame::Method(0, Class::Connection(connection::Methods::Start(connection::Start {
+            version_major:     0,
+            version_minor:     9,
+            server_properties: FieldTable::new(),
+            mechanisms:        "PLAIN".to_string(),
+            locales:           locales.to_string(),
+        })));
+
+        let mut conn = Connection::new();
+        conn.set_locales(&["fr_FR".to_string(), "en_US".to_string()]);
+        conn.state = ConnectionState::Connecting(ConnectingState::SentProtocolHeader);
+        conn.handle_frame(start("en_US fr_FR")).unwrap();
+        match conn.next_frame() {
+            Some(Frame::Method(0, Class::Connection(connection::Methods::StartOk(start_ok)))) => assert_eq!(start_ok.locale, "fr_FR"),
+            f => panic!("expected a connection.start-ok, got {:?}", f),
+        }
+
+        let mut conn = Connection::new();
+        conn.state = ConnectionState::Connecting(ConnectingState::SentProtocolHeader);
+        assert_eq!(conn.handle_frame(start("de_DE")), Err(error::Error::NoSupportedLocale("de_DE".to_string())));
+        assert_eq!(conn.state, ConnectionState::Error);
+        assert_eq!(conn.next_frame(), None);
+    }
+
+    #[test]
+    fn connection_blocked() {
+        let _ = env_logger::try_init();
+
+        let mut conn = Connection::new();
+        conn.state = ConnectionState::Connected;
+
+        let blocked = Frame::Method(0, Class::Connection(connection::Methods::Blocked(connection::Blocked {
+            reason: "low on memory".to_string(),
+        })));
+        conn.handle_frame(blocked).unwrap();
+        assert!(conn.is_blocked());
+        assert_eq!(conn.next_event(), Some(ConnectionEvent::Blocked("low on memory".to_string())));
+
+        let unblocked = Frame::Method(0, Class::Connection(connection::Methods::Unblocked(connection::Unblocked {})));
+        conn.handle_frame(unblocked).unwrap();
+        assert!(!conn.is_blocked());
+        assert_eq!(conn.next_event(), Some(ConnectionEvent::Unblocked));
+        assert_eq!(conn.next_event(), None);
+    }
+
+    #[test]
+    fn missed_heartbeats() {
+        let _ = env_logger::try_init();
+
+        let mut conn = Connection::new();
+        conn.state = ConnectionState::Connected;
+        conn.configuration.heartbeat = 10;
+
+        let start = Instant::now();
+        conn.poll_timers(start).unwrap();
+        assert_eq!(conn.next_timeout(), Some(start + Duration::from_secs(10)));
+        assert_eq!(conn.next_frame(), None);
+
+        // nothing was sent for an interval, a heartbeat is due
+        conn.poll_timers(start + Duration::from_secs(10)).unwrap();
+        assert_eq!(conn.next_frame(), Some(Frame::Heartbeat(0)));
+
+        // traffic from the server resets the missed heartbeats
+        conn.handle_frame(Frame::Heartbeat(0)).unwrap();
+        conn.poll_timers(start + Duration::from_secs(15)).unwrap();
+        assert_eq!(conn.next_timeout(), Some(start + Duration::from_secs(25)));
+
+        conn.poll_timers(start + Duration::from_secs(34)).unwrap();
+        assert_eq!(conn.state, ConnectionState::Connected);
+        assert_eq!(conn.poll_timers(start + Duration::from_secs(35)), Err(error::Error::MissedHeartbeats));
+        assert_eq!(conn.state, ConnectionState::Error);
+    }
+
+    #[test]
+    fn update_secret() {
+        let _ = env_logger::try_init();
+
+        let mut conn = Connection::new();
+        assert_eq!(conn.update_secret("token", "expired"), Err(error::Error::NotConnected));
+        conn.state = ConnectionState::Connected;
+
+        let request_id = conn.update_secret("token", "expired").unwrap();
+        let update_secret = Frame::Method(0, Class::Connection(connection::Methods::UpdateSecret(connection::UpdateSecret {
+            new_secret: "token".to_string(),
+            reason:     "expired".to_string(),
+        })));
+        assert_eq!(conn.next_frame(), Some(update_secret));
+        assert_eq!(conn.is_finished(request_id), None);
+
+        let update_secret_ok = Frame::Method(0, Class::Connection(connection::Methods::UpdateSecretOk(connection::UpdateSecretOk {})));
+        conn.handle_frame(update_secret_ok).unwrap();
+        assert_eq!(conn.is_finished(request_id), Some(true));
+    }
+
+    #[test]
+    fn unsupported_protocol_version() {
+        let _ = env_logger::try_init();
+
+        let handshake_error = |data: &[u8]| {
+            let mut conn = Connection::new();
+            conn.state = ConnectionState::Connecting(ConnectingState::SentProtocolHeader);
+            let err = conn.parse(data).unwrap_err();
+            assert_eq!(conn.state, ConnectionState::Error);
+            err.get_ref().and_then(|e| e.downcast_ref::<error::Error>()).cloned()
+        };
+
+        let version = ProtocolVersion { id: 1, major: 1, minor: 0, revision: 10 };
+        assert_eq!(handshake_error(b"AMQP\x01\x01\x00\x0a"), Some(error::Error::UnsupportedProtocolVersion(version)));
+        assert_eq!(version.to_string(), "AMQP 0-10");
+        assert_eq!(handshake_error(b"HTTP/1.1 400 Bad Request\r\n"), Some(error::Error::NotAMQPServer));
+
+        let mut conn = Connection::new();
+        conn.state = ConnectionState::Connecting(ConnectingState::SentProtocolHeader);
+        assert_eq!(conn.parse(b"AMQ").unwrap(), (0, conn.state));
+    }
+
+    #[test]
+    fn frame_too_large() {
+        let _ = env_logger::try_init();
+
+        let mut conn = Connection::new();
+        conn.state = ConnectionState::Connected;
+        conn.configuration.frame_max = 4096;
+
+        // body frame on channel 1 announcing 8192 bytes of payload
+        let header = [constants::FRAME_BODY, 0, 1, 0, 0, 0x20, 0];
+        let err = conn.parse(&header).unwrap_err();
+        assert_eq!(err.get_ref().and_then(|e| e.downcast_ref::<error::Error>()), Some(&error::Error::FrameTooLarge(8200, 4096)));
+        assert_eq!(conn.state, ConnectionState::Closing(ClosingState::SentClose));
+        match conn.next_frame() {
+            Some(Frame::Method(0, Class::Connection(connection::Methods::Close(close)))) => assert_eq!(close.reply_code, 501),
+            f => panic!("expected a connection.close, got {:?}", f),
+        }
+
+        // before frame_max is negotiated, the minimum frame size is enforced
+        let mut conn = Connection::new();
+        conn.state = ConnectionState::Connecting(ConnectingState::SentStartOk);
+        let header = [constants::FRAME_METHOD, 0, 0, 0xff, 0xff, 0xff, 0xff];
+        let err = conn.parse(&header).unwrap_err();
+        assert_eq!(err.get_ref().and_then(|e| e.downcast_ref::<error::Error>()), Some(&error::Error::FrameTooLarge(u32::MAX, FRAME_MIN_SIZE)));
+    }
+
+    fn protocol_violation(frame: &[u8]) -> (error::Error, u16) {
+        let (mut conn, _) = connected_channel();
+
+        let err = conn.parse(frame).unwrap_err();
+        let err = err.get_ref().and_then(|e| e.downcast_ref::<error::Error>()).cloned().expect("expected a protocol error");
+        assert_eq!(conn.state, ConnectionState::Closing(ClosingState::SentClose));
+        match conn.next_frame() {
+            Some(Frame::Method(0, Class::Connection(connection::Methods::Close(close)))) => (err, close.reply_code),
+            f => panic!("expected a connection.close, got {:?}", f),
+        }
+    }
+
+    #[test]
+    fn invalid_frames_close_the_connection() {
+        let _ = env_logger::try_init();
+
+        let mut data = [0u8; 64];
+        let flow = Class::Channel(channel::Methods::Flow(channel::Flow { active: false }));
+        let size = gen_method_frame((&mut data, 0), 1, &flow).unwrap().1;
+
+        let mut bad_end = data;
+        bad_end[size - 1] = 0;
+        assert_eq!(protocol_violation(&bad_end[..size]), (error::Error::InvalidFrameEnd(0), 501));
+
+        let mut bad_type = data;
+        bad_type[0] = 42;
+        assert_eq!(protocol_violation(&bad_type[..size]), (error::Error::UnknownFrameType(42), 501));
+
+        // channel.flow announcing a payload too short for its arguments
+        let truncated = [constants::FRAME_METHOD, 0, 1, 0, 0, 0, 4, 0, 20, 0, 20, constants::FRAME_END];
+        assert_eq!(protocol_violation(&truncated), (error::Error::InvalidFramePayload(1), 502));
+
+        let body = [constants::FRAME_BODY, 0, 0, 0, 0, 0, 1, b'x', constants::FRAME_END];
+        assert_eq!(protocol_violation(&body), (error::Error::UnexpectedFrame("content body on channel 0".to_string()), 505));
+
+        // no basic.deliver announced the content on channel 1
+        let body = [constants::FRAME_BODY, 0, 1, 0, 0, 0, 1, b'x', constants::FRAME_END];
+        assert_eq!(protocol_violation(&body), (error::Error::UnexpectedFrame("content body on channel 1 in state Connected".to_string()), 505));
+    }
+
+    #[test]
+    fn parse_waits_for_whole_frame() {
+        let _ = env_logger::try_init();
+
+        let (mut conn, channel_id) = connected_channel();
+
+        let mut data = [0u8; 64];
+        let flow = Class::Channel(channel::Methods::Flow(channel::Flow { active: false }));
+        let size = gen_method_frame((&mut data, 0), channel_id, &flow).unwrap().1;
+
+        // the header is enough to know how much is missing, the payload is not parsed yet
+        assert_eq!(frame(&data[..10]), IResult::Incomplete(nom::Needed::Size(size)));
+        assert_eq!(conn.parse(&data[..size - 1]).unwrap(), (0, ConnectionState::Connected));
+        assert_eq!(conn.parse(&data[..size]).unwrap(), (size, ConnectionState::Connected));
+        assert!(!conn.channels[&channel_id].send_flow);
+
+        // a frame with an invalid frame end is rejected
+        data[size - 1] = 0;
+        assert!(conn.parse(&data[..size]).is_err());
+    }
+
+    #[test]
+    fn deliveries_on_several_queues() {
+        let _ = env_logger::try_init();
+
+        use queue::Queue;
+
+        let (mut conn, channel_id) = connected_channel();
+
+        let queues = ["first", "second", "third"];
+        for queue_name in &queues {
+            if let Some(c) = conn.channels.get_mut(&channel_id) {
+                c.queues.insert(queue_name.to_string(), Queue::new(queue_name.to_string(), 0, 0));
+            }
+            let consumer_tag = format!("{}-consumer", queue_name);
+            conn.basic_consume(channel_id, 0, queue_name.to_string(), consumer_tag.clone(), false, false, false, false, FieldTable::new()).unwrap();
+            conn.handle_frame(Frame::Method(channel_id, Class::Basic(basic::Methods::ConsumeOk(basic::ConsumeOk { consumer_tag })))).unwrap();
+        }
+
+        let deliver_frame = Frame::Method(channel_id, Class::Basic(basic::Methods::Deliver(basic::Deliver {
+            consumer_tag: "second-consumer".to_string(),
+            delivery_tag: 1,
+            redelivered:  false,
+            exchange:     "".to_string(),
+            routing_key:  "second".to_string(),
+        })));
+        conn.handle_frame(deliver_frame).unwrap();
+        assert_eq!(conn.get_state(channel_id), Some(ChannelState::WillReceiveContent("second".to_string(), Some("second-consumer".to_string()))));
+
+        let header_frame = Frame::Header(channel_id, 60, ContentHeader {
+            class_id:   60,
+            weight:     0,
+            body_size:  2,
+            properties: basic::Properties::default(),
+        });
+        conn.handle_frame(header_frame).unwrap();
+        conn.handle_frame(Frame::Body(channel_id, Bytes::from_static(b"{}"))).unwrap();
+        assert_eq!(conn.get_state(channel_id), Some(ChannelState::Connected));
+
+        assert_eq!(conn.next_delivery(channel_id, "first", "first-consumer"), None);
+        assert_eq!(conn.next_delivery(channel_id, "third", "third-consumer"), None);
+        let delivery = conn.next_delivery(channel_id, "second", "second-consumer").unwrap();
+        assert_eq!(delivery.delivery_tag, 1);
+        assert_eq!(delivery.data, b"{}".to_vec());
+    }
+
+    #[test]
+    fn channel_flow_stops_publishes() {
+        let _ = env_logger::try_init();
+
+        let (mut conn, channel_id) = connected_channel();
+
+        conn.handle_frame(Frame::Method(channel_id, Class::Channel(channel::Methods::Flow(channel::Flow { active: false })))).unwrap();
+        assert_eq!(conn.frame_queue.back(), Some(&Frame::Method(channel_id, Class::Channel(channel::Methods::FlowOk(channel::FlowOk { active: false })))));
+        assert_eq!(conn.basic_publish(channel_id, 0, "".to_string(), "paused".to_string(), false, false), Err(error::Error::FlowStopped));
+
+        conn.handle_frame(Frame::Method(channel_id, Class::Channel(channel::Methods::Flow(channel::Flow { active: true })))).unwrap();
+        assert_eq!(conn.frame_queue.back(), Some(&Frame::Method(channel_id, Class::Channel(channel::Methods::FlowOk(channel::FlowOk { active: true })))));
+        assert!(conn.basic_publish(channel_id, 0, "".to_string(), "paused".to_string(), false, false).is_ok());
+    }
+
+    #[test]
+    fn content_frames_are_slices_of_the_payload() {
+        let _ = env_logger::try_init();
+
+        let (mut conn, channel_id) = connected_channel();
+        conn.configuration.frame_max = 48;
+
+        let payload = Bytes::from((0..100u8).collect::<Vec<u8>>());
+        conn.send_content_frames(channel_id, 60, payload.clone(), basic::Properties::default());
+        conn.frame_queue.pop_front();
+        let chunks: Vec<Bytes> = conn.frame_queue.drain(..).map(|frame| match frame {
+            Frame::Body(_, data) => data,
+            f                    => panic!("expected a body frame, got {:?}", f),
+        }).collect();
+        assert_eq!(chunks, vec![payload.slice(0, 40), payload.slice(40, 80), payload.slice(80, 100)]);
+        // small slices are stored inline by `Bytes`, larger ones share the payload
+        assert_eq!(chunks[1].as_ptr(), payload[40..].as_ptr());
+    }
+
+    #[test]
+    fn transactions() {
+        let _ = env_logger::try_init();
+
+        let (mut conn, tx_channel) = connected_channel();
+        let confirm_channel = conn.create_channel().unwrap();
+        conn.set_channel_state(confirm_channel, ChannelState::Connected);
+
+        let request_id = conn.tx_select(tx_channel).unwrap();
+        assert_eq!(conn.confirm_select(tx_channel, false), Err(error::Error::ConfirmAndTxMode));
+        conn.handle_frame(Frame::Method(tx_channel, Class::Tx(tx::Methods::SelectOk(tx::SelectOk {})))).unwrap();
+        assert_eq!(conn.is_finished(request_id), Some(true));
+        assert_eq!(conn.confirm_select(tx_channel, false), Err(error::Error::ConfirmAndTxMode));
+
+        let request_id = conn.tx_commit(tx_channel).unwrap();
+        conn.handle_frame(Frame::Method(tx_channel, Class::Tx(tx::Methods::CommitOk(tx::CommitOk {})))).unwrap();
+        assert_eq!(conn.is_finished(request_id), Some(true));
+
+        let request_id = conn.tx_rollback(tx_channel).unwrap();
+        conn.handle_frame(Frame::Method(tx_channel, Class::Tx(tx::Methods::RollbackOk(tx::RollbackOk {})))).unwrap();
+        assert_eq!(conn.is_finished(request_id), Some(true));
+
+        conn.confirm_select(confirm_channel, false).unwrap();
+        assert_eq!(conn.tx_select(confirm_channel), Err(error::Error::ConfirmAndTxMode));
+    }
+
+    #[test]
+    fn server_cancels_consumer() {
+        let _ = env_logger::try_init();
+
+        use queue::{Consumer, Queue};
+
+        let (mut conn, channel_id) = connected_channel();
+        let queue_name = "deleted".to_string();
+        let consumer_tag = "consumer-tag".to_string();
+        let mut queue = Queue::new(queue_name.clone(), 0, 0);
+        let mut messages = VecDeque::new();
+        messages.push_back(Delivery::new(1, "".to_string(), queue_name.clone(), false));
+        queue.consumers.insert(consumer_tag.clone(), Consumer {
+            tag: consumer_tag.clone(),
+            no_local: false,
+            no_ack: false,
+            exclusive: false,
+            nowait: false,
+            current_message: None,
+            messages,
+            cancelled: false,
+        });
+        if let Some(c) = conn.channels.get_mut(&channel_id) {
+            c.queues.insert(queue_name.clone(), queue);
+            c.consumer_index.insert(consumer_tag.clone(), queue_name.clone());
+        }
+
+        let cancel_frame = Frame::Method(channel_id, Class::Basic(basic::Methods::Cancel(basic::Cancel {
+            consumer_tag: consumer_tag.clone(),
+            nowait:       false,
+        })));
+        assert!(conn.has_consumer_tag(channel_id, &consumer_tag));
+        conn.handle_frame(cancel_frame).unwrap();
+        assert_eq!(conn.frame_queue.back(), Some(&Frame::Method(channel_id, Class::Basic(basic::Methods::CancelOk(basic::CancelOk {
+            consumer_tag: consumer_tag.clone(),
+        })))));
+        assert!(!conn.has_consumer_tag(channel_id, &consumer_tag));
+
+        // the message received before the cancellation can still be consumed
+        assert!(conn.has_consumer(channel_id, &queue_name, &consumer_tag));
+        assert_eq!(conn.next_delivery(channel_id, &queue_name, &consumer_tag).map(|d| d.delivery_tag), Some(1));
+        assert!(!conn.has_consumer(channel_id, &queue_name, &consumer_tag));
+        assert!(!conn.has_consumer_tag(channel_id, &consumer_tag));
+        assert_eq!(conn.next_delivery(channel_id, &queue_name, &consumer_tag), None);
+    }
+
+    #[test]
+    fn publisher_confirms() {
+        let _ = env_logger::try_init();
+
+        let (mut conn, channel_id) = connected_channel();
+        conn.confirm_select(channel_id, false).unwrap();
+        conn.handle_frame(Frame::Method(channel_id, Class::Confirm(confirm::Methods::SelectOk(confirm::SelectOk {})))).unwrap();
+
+        let tags: Vec<u64> = (0..5).map(|_| {
+            conn.basic_publish(channel_id, 0, "".to_string(), "confirmed".to_string(), false, false).unwrap()
+        }).collect();
+        assert_eq!(tags, vec![1, 2, 3, 4, 5]);
+
+        // confirms do not get in the way of the answers to other methods
+        let request_id = conn.basic_qos(channel_id, 0, 10, false).unwrap();
+        conn.handle_frame(Frame::Method(channel_id, Class::Basic(basic::Methods::QosOk(basic::QosOk {})))).unwrap();
+        assert_eq!(conn.is_finished(request_id), Some(true));
+
+        let ack = |delivery_tag, multiple| Frame::Method(channel_id, Class::Basic(basic::Methods::Ack(basic::Ack { delivery_tag, multiple })));
+        let nack = |delivery_tag, multiple| Frame::Method(channel_id, Class::Basic(basic::Methods::Nack(basic::Nack { delivery_tag, multiple, requeue: false })));
+
+        conn.handle_frame(ack(2, true)).unwrap();
+        conn.handle_frame(nack(3, false)).unwrap();
+        conn.handle_frame(ack(4, false)).unwrap();
+        {
+            let c = &conn.channels[&channel_id];
+            assert_eq!(c.acked.iter().cloned().collect::<Vec<_>>(), vec![1, 2, 4]);
+            assert_eq!(c.nacked.iter().cloned().collect::<Vec<_>>(), vec![3]);
+            assert_eq!(c.unacked.iter().cloned().collect::<Vec<_>>(), vec![5]);
+        }
+
+        conn.handle_frame(nack(5, true)).unwrap();
+        let c = &conn.channels[&channel_id];
+        assert_eq!(c.acked.iter().cloned().collect::<Vec<_>>(), vec![1, 2, 4]);
+        assert_eq!(c.nacked.iter().cloned().collect::<Vec<_>>(), vec![3, 5]);
+        assert!(c.unacked.is_empty());
+        assert_eq!(c.state, ChannelState::Connected);
+    }
+
+    #[test]
+    fn basic_return_on_confirm_channel() {
+        let _ = env_logger::try_init();
+
+        let (mut conn, channel_id) = connected_channel();
+        if let Some(c) = conn.channels.get_mut(&channel_id) {
+            c.confirm = true;
+            c.message_count = 1;
+        }
+
+        let delivery_tag = conn.basic_publish(channel_id, 0, "".to_string(), "nowhere".to_string(), true, false).unwrap();
+        assert_eq!(delivery_tag, 1);
+
+        let return_frame = Frame::Method(channel_id, Class::Basic(basic::Methods::Return(basic::Return {
+            reply_code:  312,
+            reply_text:  "NO_ROUTE".to_string(),
+            exchange:    "".to_string(),
+            routing_key: "nowhere".to_string(),
+        })));
+        conn.handle_frame(return_frame).unwrap();
+        assert_eq!(conn.get_state(channel_id), Some(ChannelState::WillReceiveReturn));
+
+        let header_frame = Frame::Header(channel_id, 60, ContentHeader {
+            class_id:   60,
+            weight:     0,
+            body_size:  2,
+            properties: basic::Properties::default(),
+        });
+        conn.handle_frame(header_frame).unwrap();
+        assert_eq!(conn.get_state(channel_id), Some(ChannelState::ReceivingReturn(2)));
+        assert!(!conn.has_pending_returned_messages());
+
+        conn.handle_frame(Frame::Body(channel_id, Bytes::from_static(b"{}"))).unwrap();
+        assert_eq!(conn.get_state(channel_id), Some(ChannelState::Connected));
+        assert!(conn.has_pending_returned_messages());
+
+        let ack_frame = Frame::Method(channel_id, Class::Basic(basic::Methods::Ack(basic::Ack {
+            delivery_tag,
+            multiple: false,
+        })));
+        conn.handle_frame(ack_frame).unwrap();
+
+        let message = conn.next_returned_message(channel_id).unwrap();
+        assert_eq!(message.reply_code, 312);
+        assert_eq!(message.reply_text, "NO_ROUTE");
+        assert_eq!(message.delivery.routing_key, "nowhere");
+        assert_eq!(message.delivery.data, b"{}".to_vec());
+        assert_eq!(conn.next_returned_message(channel_id), None);
+
+        let channel = &conn.channels[&channel_id];
+        assert!(channel.acked.contains(&delivery_tag));
+        assert!(channel.returned.contains(&delivery_tag));
+    }
+
+    #[test]
+    fn basic_return_belongs_to_the_next_confirm() {
+        let _ = env_logger::try_init();
+
+        let (mut conn, channel_id) = connected_channel();
+        if let Some(c) = conn.channels.get_mut(&channel_id) {
+            c.confirm = true;
+            c.message_count = 1;
+        }
+
+        // the first message is routed, its ack is still pending when the second one is returned
+        let routed = conn.basic_publish(channel_id, 0, "".to_string(), "queue".to_string(), true, false).unwrap();
+        let returned = conn.basic_publish(channel_id, 0, "".to_string(), "nowhere".to_string(), true, false).unwrap();
+
+        conn.handle_frame(Frame::Method(channel_id, Class::Basic(basic::Methods::Return(basic::Return {
+            reply_code:  312,
+            reply_text:  "NO_ROUTE".to_string(),
+            exchange:    "".to_string(),
+            routing_key: "nowhere".to_string(),
+        })))).unwrap();
+        conn.handle_frame(Frame::Header(channel_id, 60, ContentHeader {
+            class_id:   60,
+            weight:     0,
+            body_size:  0,
+            properties: basic::Properties::default(),
+        })).unwrap();
+        assert!(conn.channels[&channel_id].returned.is_empty());
+
+        conn.handle_frame(Frame::Method(channel_id, Class::Basic(basic::Methods::Ack(basic::Ack {
+            delivery_tag: returned,
+            multiple:     false,
+        })))).unwrap();
+        conn.handle_frame(Frame::Method(channel_id, Class::Basic(basic::Methods::Ack(basic::Ack {
+            delivery_tag: routed,
+            multiple:     false,
+        })))).unwrap();
+
+        let channel = &conn.channels[&channel_id];
+        assert!(channel.acked.contains(&routed));
+        assert!(channel.acked.contains(&returned));
+        assert!(!channel.returned.contains(&routed));
+        assert!(channel.returned.contains(&returned));
+    }
+
+    #[test]
+    fn basic_returns_before_a_multiple_confirm() {
+        let _ = env_logger::try_init();
+
+        let (mut conn, channel_id) = connected_channel();
+        if let Some(c) = conn.channels.get_mut(&channel_id) {
+            c.confirm = true;
+            c.message_count = 1;
+        }
+
+        let first = conn.basic_publish(channel_id, 0, "".to_string(), "nowhere".to_string(), true, false).unwrap();
+        let second = conn.basic_publish(channel_id, 0, "".to_string(), "nowhere".to_string(), true, false).unwrap();
+
+        for _ in 0..2 {
+            conn.handle_frame(Frame::Method(channel_id, Class::Basic(basic::Methods::Return(basic::Return {
+                reply_code:  312,
+                reply_text:  "NO_ROUTE".to_string(),
+                exchange:    "".to_string(),
+                routing_key: "nowhere".to_string(),
+            })))).unwrap();
+            conn.handle_frame(Frame::Header(channel_id, 60, ContentHeader {
+                class_id:   60,
+                weight:     0,
+                body_size:  0,
+                properties: basic::Properties::default(),
+            })).unwrap();
+        }
+        assert_eq!(conn.channels[&channel_id].pending_returns, 2);
+
+        conn.handle_frame(Frame::Method(channel_id, Class::Basic(basic::Methods::Ack(basic::Ack {
+            delivery_tag: second,
+            multiple:     true,
+        })))).unwrap();
+
+        let channel = &conn.channels[&channel_id];
+        assert_eq!(channel.pending_returns, 0);
+        assert!(channel.returned.contains(&first));
+        assert!(channel.returned.contains(&second));
+    }
+
+    #[test]
+    fn channel_ids_are_reclaimed() {
+        let _ = env_logger::try_init();
+
+        let mut conn = Connection::new();
+        conn.state = ConnectionState::Connected;
+        conn.configuration.channel_max = 2;
+
+        let first = conn.create_channel().unwrap();
+        conn.set_channel_state(first, ChannelState::Connected);
+        let second = conn.create_channel().unwrap();
+        conn.set_channel_state(second, ChannelState::Connected);
+        assert_eq!((first, second), (1, 2));
+        assert_eq!(conn.create_channel(), Err(error::Error::ChannelLimitReached(2)));
+
+        let request_id = conn.channel_close(first, 200, "OK".to_string(), 0, 0).unwrap();
+        let close_ok = Frame::Method(first, Class::Channel(channel::Methods::CloseOk(channel::CloseOk {})));
+        conn.handle_frame(close_ok).unwrap();
+        assert_eq!(conn.is_finished(request_id), Some(true));
+        assert_eq!(conn.get_state(first), None);
+        assert!(!conn.channels.contains_key(&first));
+
+        let server_close = Frame::Method(second, Class::Channel(channel::Methods::Close(channel::Close {
+            reply_code: 404,
+            reply_text: "NOT_FOUND - no queue 'missing'".to_string(),
+            class_id:   50,
+            method_id:  10,
+        })));
+        let second_serial = conn.get_serial(second).unwrap();
+        conn.handle_frame(server_close).unwrap();
+        assert_eq!(conn.get_state(second), None);
+        assert!(!conn.channels.contains_key(&second));
+        assert_eq!(conn.get_close_reason(second), Some(CloseReason {
+            reply_code: 404,
+            reply_text: "NOT_FOUND - no queue 'missing'".to_string(),
+            class_id:   50,
+            method_id:  10,
+        }));
+        assert_eq!(conn.frame_queue.back(), Some(&Frame::Method(second, Class::Channel(channel::Methods::CloseOk(channel::CloseOk {})))));
+
+        assert_eq!(conn.create_channel(), Ok(1));
+        assert_eq!(conn.create_channel(), Ok(2));
+        assert_eq!(conn.get_close_reason(2), None);
+        assert_ne!(conn.get_serial(2), Some(second_serial));
+        assert_eq!(conn.get_closed_channel_reason(2, second_serial).map(|r| r.reply_code), Some(404));
+
+        // a channel waiting for close-ok keeps its id
+        conn.set_channel_state(1, ChannelState::Connected);
+        conn.channel_close(1, 200, "OK".to_string(), 0, 0).unwrap();
+        assert_eq!(conn.create_channel(), Err(error::Error::ChannelLimitReached(2)));
+    }
+
+    #[test]
+    fn channel_max_zero_is_unlimited() {
+        let _ = env_logger::try_init();
+
+        let mut conn = Connection::new();
+        conn.state = ConnectionState::Connected;
+        conn.configuration.channel_max = 0;
+
+        assert_eq!(conn.create_channel(), Ok(1));
+        conn.channel_index = u16::MAX - 1;
+        assert_eq!(conn.create_channel(), Ok(u16::MAX));
+        assert_eq!(conn.create_channel(), Ok(2));
+    }
+
+    #[test]
+    fn connection_secure_rounds() {
+        use auth::CustomMechanism;
+        use sasl::client::Mechanism;
+        use sasl::common::Credentials;
+
+        let _ = env_logger::try_init();
+
+        struct Echo;
+
+        impl Mechanism for Echo {
+            fn name(&self) -> &str { "ECHO" }
+            fn from_credentials(_: Credentials) -> result::Result<Echo, String> { Ok(Echo) }
+            fn response(&mut self, challenge: &[u8]) -> result::Result<Vec<u8>, String> {
+                let mut response = b"re: ".to_vec();
+                response.extend(challenge);
+                Ok(response)
+            }
+        }
+
+        let mechanism = SASLMechanism::Custom(CustomMechanism::new("ECHO", |c| {
+            Echo::from_credentials(c).map(|m| Box::new(m) as Box<dyn Mechanism + Send>)
+        }));
+        let mut conn = Connection::new();
+        conn.sasl_session = Some(SASLSession::new(&mechanism, Credentials::default()).unwrap());
+        conn.state = ConnectionState::Connecting(ConnectingState::SentStartOk);
+
+        for challenge in &["first", "second"] {
+            let secure = Frame::Method(0, Class::Connection(connection::Methods::Secure(connection::Secure {
+                challenge: challenge.as_bytes().to_vec(),
+            })));
+            conn.handle_frame(secure).unwrap();
+            assert_eq!(conn.state, ConnectionState::Connecting(ConnectingState::SentSecure));
+            let secure_ok = Frame::Method(0, Class::Connection(connection::Methods::SecureOk(connection::SecureOk {
+                response: format!("re: {}", challenge).into_bytes(),
+            })));
+            assert_eq!(conn.next_frame(), Some(secure_ok));
+        }
+
+        let tune = Frame::Method(0, Class::Connection(connection::Methods::Tune(connection::Tune {
+            channel_max: 2047,
+            frame_max:   131072,
+            heartbeat:   60,
+        })));
+        conn.handle_frame(tune).unwrap();
+        assert_eq!(conn.state, ConnectionState::Connecting(ConnectingState::SentOpen));
+        assert!(conn.sasl_session.is_none());
+    }
 }
@@ -1,4 +1,9 @@
+use std::fmt;
+use std::error::Error as StdError;
 use api::ChannelState;
+use format::frame::ProtocolVersion;
+use connection::CloseReason;
+use amq_protocol::protocol::AMQPHardError;
 
 #[derive(Clone,Debug,PartialEq)]
 pub enum Error {
@@ -11,8 +16,84 @@ pub enum Error {
   InvalidChannel,
   NotConnected,
   UnexpectedAnswer,
+  /// none of the requested SASL mechanisms is in the list sent by the server
+  NoSupportedSASLMechanism(String),
+  SASLError(String),
+  /// none of the requested locales is in the list sent by the server
+  NoSupportedLocale(String),
+  /// the server does not support this protocol extension
+  MissingCapability(String),
+  /// nothing was received from the server for two heartbeat intervals
+  MissedHeartbeats,
+  /// the server answered the protocol header with the version it supports
+  UnsupportedProtocolVersion(ProtocolVersion),
+  /// the server did not answer the protocol header with AMQP data
+  NotAMQPServer,
+  /// the server sent a frame of the first size, larger than the negotiated frame_max
+  FrameTooLarge(u32, u32),
+  /// all the channel ids up to the negotiated channel_max are in use
+  ChannelLimitReached(u16),
+  /// the server closed the channel with this reason
+  ChannelClosed(CloseReason),
+  /// confirm mode and transactions cannot be enabled on the same channel
+  ConfirmAndTxMode,
+  /// the server asked to stop publishing on this channel with channel.flow
+  FlowStopped,
+  /// a message property does not fit in its AMQP encoding
+  InvalidProperty(String),
+  /// the server sent a frame of an unknown type
+  UnknownFrameType(u8),
+  /// the server sent a frame that does not end with the frame-end octet
+  InvalidFrameEnd(u8),
+  /// the payload of a method or content header frame sent on this channel could not be decoded
+  InvalidFramePayload(u16),
+  /// the server sent a frame that is not allowed at this point of the conversation
+  UnexpectedFrame(String),
+  /// the channel went to the error state before channel.open-ok
+  ChannelOpenFailed(u16),
+  /// basic.get found no message in this queue
+  EmptyQueue(String),
 }
 
+impl Error {
+  /// reply code of the connection.close sent when the server broke the protocol
+  pub fn hard_error(&self) -> Option<AMQPHardError> {
+    match *self {
+      Error::FrameTooLarge(..)      |
+      Error::UnknownFrameType(_)    |
+      Error::InvalidFrameEnd(_)     => Some(AMQPHardError::FRAMEERROR),
+      Error::InvalidFramePayload(_) => Some(AMQPHardError::SYNTAXERROR),
+      Error::UnexpectedFrame(_)     => Some(AMQPHardError::UNEXPECTEDFRAME),
+      _                             => None,
+    }
+  }
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Error::UnsupportedProtocolVersion(version) => write!(f, "the server does not support AMQP 0-9-1, it wants {}", version),
+      Error::NotAMQPServer                       => write!(f, "the server did not answer with AMQP, this is not an AMQP server"),
+      Error::FrameTooLarge(size, frame_max)      => write!(f, "the server sent a frame of {} bytes, larger than frame_max ({})", size, frame_max),
+      Error::ChannelLimitReached(channel_max)    => write!(f, "the maximum number of channels for this connection ({}) has been reached", channel_max),
+      Error::ChannelClosed(ref reason)           => write!(f, "channel closed by server: {} {} (class {}, method {})",
+                                                      reason.reply_code, reason.reply_text, reason.class_id, reason.method_id),
+      Error::ConfirmAndTxMode                    => write!(f, "confirm mode and transactions cannot be enabled on the same channel"),
+      Error::FlowStopped                         => write!(f, "the server stopped the flow of messages on this channel"),
+      Error::InvalidProperty(ref message)        => write!(f, "invalid message property: {}", message),
+      Error::UnknownFrameType(frame_type)        => write!(f, "the server sent a frame of unknown type {}", frame_type),
+      Error::InvalidFrameEnd(frame_end)          => write!(f, "the server sent a frame ending with {} instead of the frame-end octet", frame_end),
+      Error::InvalidFramePayload(channel_id)     => write!(f, "the server sent a frame that could not be decoded on channel {}", channel_id),
+      Error::UnexpectedFrame(ref message)        => write!(f, "the server sent an unexpected frame: {}", message),
+      Error::ChannelOpenFailed(channel_id)       => write!(f, "channel {} could not be opened", channel_id),
+      Error::EmptyQueue(ref queue)               => write!(f, "queue {:?} has no message to get", queue),
+      ref e                                      => write!(f, "{:?}", e),
+    }
+  }
+}
+
+impl StdError for Error {}
+
 #[derive(Clone,Debug,PartialEq)]
 pub struct InvalidState {
     pub expected: ChannelState,
@@ -1,6 +1,8 @@
+use std::fmt;
 use amq_protocol::protocol::{constants, metadata};
+use bytes::Bytes;
 use cookie_factory::*;
-use nom::{be_u8,be_u16,be_u32,IResult};
+use nom::{be_u8,be_u16,be_u32,ErrorKind,IResult,Needed};
 use format::content::*;
 use generated::*;
 use generated::basic::{self, gen_properties};
@@ -12,6 +14,37 @@ named!(pub protocol_header<&[u8]>,
   )
 );
 
+/// protocol version sent back by a server that does not support AMQP 0-9-1
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct ProtocolVersion {
+  pub id:       u8,
+  pub major:    u8,
+  pub minor:    u8,
+  pub revision: u8,
+}
+
+impl fmt::Display for ProtocolVersion {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match (self.id, self.major) {
+      // AMQP 0-8 and 0-10 headers start with the class and instance, both set to 1
+      (1, 1) => write!(f, "AMQP {}-{}", self.minor, self.revision),
+      (0, 0) => write!(f, "AMQP 0-{}-{}", self.minor, self.revision),
+      _      => write!(f, "AMQP {}.{}.{} (protocol id {})", self.major, self.minor, self.revision, self.id),
+    }
+  }
+}
+
+named!(pub protocol_version<ProtocolVersion>,
+  do_parse!(
+    tag!(metadata::NAME.as_bytes()) >>
+    id:       be_u8 >>
+    major:    be_u8 >>
+    minor:    be_u8 >>
+    revision: be_u8 >>
+    (ProtocolVersion { id, major, minor, revision })
+  )
+);
+
 pub fn gen_protocol_header<'a>(x:(&'a mut [u8],usize)) -> Result<(&'a mut [u8],usize),GenError> {
   do_gen!(
     x,
@@ -57,27 +90,32 @@ named!(pub channel_id<Channel>,
   )
 );
 
-//FIXME: maybe parse the frame header, and leave the payload for later
+/// header of a frame, parsed before waiting for its payload
 #[derive(Clone,Copy,Debug,PartialEq,Eq)]
-pub struct RawFrame<'a> {
+pub struct FrameHeader {
   pub frame_type: FrameType,
   pub channel_id: u16,
   pub size:       u32,
-  pub payload:    &'a[u8],
 }
 
-named!(pub raw_frame<RawFrame>,
+impl FrameHeader {
+  /// size of the whole frame, header and frame end included
+  ///
+  /// it saturates instead of overflowing on 32 bits targets, check it against frame_max first
+  pub fn frame_size(&self) -> usize {
+    (self.size as usize).saturating_add(8)
+  }
+}
+
+named!(pub frame_header<FrameHeader>,
   do_parse!(
-    frame:   frame_type    >>
-    channel: be_u16        >>
-    size:    be_u32        >>
-    payload: take!(size)   >>
-             tag!(&[constants::FRAME_END]) >>
-    (RawFrame {
-      frame_type: frame,
-      channel_id: channel,
-      size:       size,
-      payload:    payload,
+    frame_type: frame_type >>
+    channel_id: be_u16     >>
+    size:       be_u32     >>
+    (FrameHeader {
+      frame_type,
+      channel_id,
+      size,
     })
   )
 );
@@ -89,24 +127,62 @@ pub enum Frame {
   //content header
   Header(u16, u16, ContentHeader),
   //content Body
-  Body(u16, Vec<u8>),
+  Body(u16, Bytes),
   Heartbeat(u16)
 }
 
-pub fn frame(input: &[u8]) -> IResult<&[u8], Frame> {
-  let (remaining, raw) = try_parse!(input, raw_frame);
-  match raw.frame_type {
-    FrameType::Header    => {
-       let (_, h) = try_parse!(raw.payload, content_header);
-      IResult::Done(remaining, Frame::Header(raw.channel_id, h.class_id, h))
+/// largest frame accepted until frame_max is negotiated, every peer has to support it
+pub const FRAME_MIN_SIZE: u32 = 4096;
+
+/// size of the frame starting at `input`, header and frame end included
+///
+/// returns `None` until the frame header is complete
+pub fn frame_size(input: &[u8]) -> Option<u32> {
+  if input.len() < 7 {
+    return None;
+  }
+  let size = (u32::from(input[3]) << 24) | (u32::from(input[4]) << 16) | (u32::from(input[5]) << 8) | u32::from(input[6]);
+  Some(size.saturating_add(8))
+}
+
+/// payload of the frame starting at `input`, once the whole frame is buffered
+///
+/// returns `Incomplete` with the size of the frame until then, without looking at the payload
+pub fn frame_payload<'a>(input: &'a [u8], header: &FrameHeader) -> IResult<&'a [u8], &'a [u8]> {
+  let size = header.frame_size();
+  if input.len() < size {
+    return IResult::Incomplete(Needed::Size(size));
+  }
+  if input[size - 1] != constants::FRAME_END {
+    return IResult::Error(error_position!(ErrorKind::Tag, &input[size - 1..]));
+  }
+  IResult::Done(&input[size..], &input[7..size - 1])
+}
+
+/// decodes the complete payload of a frame
+pub fn parse_payload<'a>(payload: &'a [u8], header: &FrameHeader) -> IResult<&'a [u8], Frame> {
+  let frame = match header.frame_type {
+    FrameType::Header    => match content_header(payload) {
+      IResult::Done(_, h) => Frame::Header(header.channel_id, h.class_id, h),
+      // the payload is complete, missing data means it is malformed
+      _                   => return IResult::Error(error_position!(ErrorKind::Complete, payload)),
     },
-    FrameType::Body      => IResult::Done(remaining, Frame::Body(raw.channel_id, Vec::from(raw.payload))),
-    FrameType::Heartbeat => IResult::Done(remaining, Frame::Heartbeat(raw.channel_id)),
-    FrameType::Method    => {
-      let (_, m) = try_parse!(raw.payload, parse_class);
-      IResult::Done(remaining, Frame::Method(raw.channel_id, m))
+    FrameType::Body      => Frame::Body(header.channel_id, Bytes::from(payload)),
+    FrameType::Heartbeat => Frame::Heartbeat(header.channel_id),
+    FrameType::Method    => match parse_class(payload) {
+      IResult::Done(_, m) => Frame::Method(header.channel_id, m),
+      _                   => return IResult::Error(error_position!(ErrorKind::Complete, payload)),
     },
-  }
+  };
+  IResult::Done(&payload[payload.len()..], frame)
+}
+
+/// parses the header first, and the payload only once the whole frame is buffered
+pub fn frame(input: &[u8]) -> IResult<&[u8], Frame> {
+  let (_, header) = try_parse!(input, frame_header);
+  let (remaining, payload) = try_parse!(input, apply!(frame_payload, &header));
+  let (_, frame) = try_parse!(payload, apply!(parse_payload, &header));
+  IResult::Done(remaining, frame)
 }
 
 pub fn gen_method_frame<'a>(input:(&'a mut [u8],usize), channel: u16, class: &Class) -> Result<(&'a mut [u8],usize),GenError> {
@@ -2,12 +2,17 @@ use connection::{Connection,ConnectionState};
 use buffer::Buffer;
 
 use std::io::{Error,ErrorKind,Read,Result,Write};
+use std::time::Instant;
 
 impl Connection {
   /// helper function to handle reading and writing repeatedly from the network until there's no more state to update
   pub fn run<T>(&mut self, stream: &mut T, send_buffer: &mut Buffer, receive_buffer: &mut Buffer) -> Result<ConnectionState>
     where T: Read + Write {
 
+    if let Err(e) = self.poll_timers(Instant::now()) {
+      return Err(Error::new(ErrorKind::TimedOut, format!("{:?}", e)));
+    }
+
     let mut write_would_block = false;
     let mut read_would_block  = false;
 
@@ -94,7 +94,7 @@
 //!
 //! ```rust,ignore
 //! conn.basic_publish(channel_id, 0, "".to_string(), "hello".to_string(), false, false).expect("basic_publish");
-//! let payload = b"Hello world!";
+//! let payload = &b"Hello world!"[..];
 //! conn.send_content_frames(channel_a, 60, payload, basic::Properties::default()));
 //!
 //! // update state
@@ -116,6 +116,7 @@
 //! ```
 
 extern crate amq_protocol;
+extern crate bytes;
 #[macro_use]
 extern crate log;
 #[macro_use]
@@ -130,9 +131,11 @@ pub mod connection;
 pub mod channel;
 pub mod queue;
 pub mod message;
+pub mod properties;
 pub mod generated;
 pub mod format;
 pub mod api;
+pub mod auth;
 pub mod error;
 pub mod types;
 
@@ -1,3 +1,4 @@
+use bytes::Bytes;
 use generated::basic;
 use types::*;
 
@@ -8,7 +9,7 @@ pub struct Delivery {
   pub routing_key:  String,
   pub redelivered:  bool,
   pub properties:   basic::Properties,
-  pub data:         Vec<u8>,
+  pub data:         Bytes,
 }
 
 impl Delivery {
@@ -19,12 +20,17 @@ impl Delivery {
       routing_key,
       redelivered,
       properties: basic::Properties::default(),
-      data:       Vec::new(),
+      data:       Bytes::new(),
     }
   }
 
-  pub fn receive_content(&mut self, data: Vec<u8>) {
-    self.data.extend(data);
+  /// the first chunk is kept as is, so single frame bodies are not copied
+  pub fn receive_content(&mut self, data: Bytes) {
+    if self.data.is_empty() {
+      self.data = data;
+    } else {
+      self.data.extend_from_slice(&data);
+    }
   }
 }
 
@@ -42,3 +48,20 @@ impl BasicGetMessage {
     }
   }
 }
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct BasicReturnMessage {
+  pub delivery:   Delivery,
+  pub reply_code: ShortUInt,
+  pub reply_text: ShortString,
+}
+
+impl BasicReturnMessage {
+  pub fn new(exchange: String, routing_key: String, reply_code: ShortUInt, reply_text: ShortString) -> BasicReturnMessage {
+    BasicReturnMessage {
+      delivery: Delivery::new(0, exchange, routing_key, false),
+      reply_code,
+      reply_text,
+    }
+  }
+}
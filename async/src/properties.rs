@@ -0,0 +1,319 @@
+use std::time::{Duration,SystemTime,UNIX_EPOCH};
+use error::Error;
+use generated::basic;
+use types::*;
+
+/// longest value of a short string, its length is encoded on one octet
+const SHORT_STRING_MAX: usize = 255;
+/// longest value of a long string or byte array, their length is encoded on four octets
+const LONG_STRING_MAX: u64 = 0xffff_ffff;
+/// highest priority defined by AMQP 0-9-1
+const PRIORITY_MAX: u8 = 9;
+
+/// whether the server should store a message on disk
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum DeliveryMode {
+  Transient,
+  Persistent,
+}
+
+impl DeliveryMode {
+  pub fn id(&self) -> ShortShortUInt {
+    match *self {
+      DeliveryMode::Transient  => 1,
+      DeliveryMode::Persistent => 2,
+    }
+  }
+}
+
+/// conversion of the values that can be stored in the headers of a message
+pub trait HeaderValue {
+  fn into_value(self) -> AMQPValue;
+}
+
+impl HeaderValue for AMQPValue {
+  fn into_value(self) -> AMQPValue { self }
+}
+
+impl HeaderValue for bool {
+  fn into_value(self) -> AMQPValue { AMQPValue::Boolean(self) }
+}
+
+impl HeaderValue for i8 {
+  fn into_value(self) -> AMQPValue { AMQPValue::ShortShortInt(self) }
+}
+
+impl HeaderValue for u8 {
+  fn into_value(self) -> AMQPValue { AMQPValue::ShortShortUInt(self) }
+}
+
+impl HeaderValue for i16 {
+  fn into_value(self) -> AMQPValue { AMQPValue::ShortInt(self) }
+}
+
+impl HeaderValue for u16 {
+  fn into_value(self) -> AMQPValue { AMQPValue::ShortUInt(self) }
+}
+
+impl HeaderValue for i32 {
+  fn into_value(self) -> AMQPValue { AMQPValue::LongInt(self) }
+}
+
+impl HeaderValue for u32 {
+  fn into_value(self) -> AMQPValue { AMQPValue::LongUInt(self) }
+}
+
+impl HeaderValue for i64 {
+  fn into_value(self) -> AMQPValue { AMQPValue::LongLongInt(self) }
+}
+
+impl HeaderValue for f32 {
+  fn into_value(self) -> AMQPValue { AMQPValue::Float(self) }
+}
+
+impl HeaderValue for f64 {
+  fn into_value(self) -> AMQPValue { AMQPValue::Double(self) }
+}
+
+impl HeaderValue for &str {
+  fn into_value(self) -> AMQPValue { AMQPValue::LongString(self.to_string()) }
+}
+
+impl HeaderValue for String {
+  fn into_value(self) -> AMQPValue { AMQPValue::LongString(self) }
+}
+
+impl HeaderValue for Vec<u8> {
+  fn into_value(self) -> AMQPValue { AMQPValue::ByteArray(self) }
+}
+
+impl HeaderValue for FieldTable {
+  fn into_value(self) -> AMQPValue { AMQPValue::FieldTable(self) }
+}
+
+impl HeaderValue for FieldArray {
+  fn into_value(self) -> AMQPValue { AMQPValue::FieldArray(self) }
+}
+
+/// typed builder for the properties of a published message
+///
+/// the values are checked against the limits of their AMQP encoding, the first
+/// invalid one is reported by `build`
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct BasicPropertiesBuilder {
+  properties: basic::Properties,
+  error:      Option<Error>,
+}
+
+impl BasicPropertiesBuilder {
+  pub fn new() -> BasicPropertiesBuilder {
+    BasicPropertiesBuilder::default()
+  }
+
+  fn invalid(mut self, message: String) -> BasicPropertiesBuilder {
+    if self.error.is_none() {
+      self.error = Some(Error::InvalidProperty(message));
+    }
+    self
+  }
+
+  fn short_string(self, name: &str, value: &str, set: fn(basic::Properties, ShortString) -> basic::Properties) -> BasicPropertiesBuilder {
+    if value.len() > SHORT_STRING_MAX {
+      return self.invalid(format!("{} is {} bytes long, the limit is {}", name, value.len(), SHORT_STRING_MAX));
+    }
+    let BasicPropertiesBuilder { properties, error } = self;
+    BasicPropertiesBuilder { properties: set(properties, value.to_string()), error }
+  }
+
+  /// MIME type of the payload, like `application/json` or `text/plain; charset=utf-8`
+  pub fn content_type(self, mime_type: &str) -> BasicPropertiesBuilder {
+    if !is_mime_type(mime_type) {
+      return self.invalid(format!("content_type {:?} is not a MIME type", mime_type));
+    }
+    self.short_string("content_type", mime_type, basic::Properties::with_content_type)
+  }
+
+  pub fn content_encoding(self, encoding: &str) -> BasicPropertiesBuilder {
+    self.short_string("content_encoding", encoding, basic::Properties::with_content_encoding)
+  }
+
+  /// adds a header, the key is limited to 255 bytes
+  pub fn header<V: HeaderValue>(mut self, key: &str, value: V) -> BasicPropertiesBuilder {
+    if key.len() > SHORT_STRING_MAX {
+      return self.invalid(format!("header name {:?} is {} bytes long, the limit is {}", key, key.len(), SHORT_STRING_MAX));
+    }
+    let value = value.into_value();
+    if let Some(message) = check_value(&value) {
+      return self.invalid(format!("header {:?}: {}", key, message));
+    }
+    self.properties.headers.get_or_insert_with(FieldTable::new).insert(key.to_string(), value);
+    self
+  }
+
+  pub fn delivery_mode(mut self, mode: DeliveryMode) -> BasicPropertiesBuilder {
+    self.properties.delivery_mode = Some(mode.id());
+    self
+  }
+
+  /// priority of the message, from 0 to 9
+  pub fn priority(mut self, priority: u8) -> BasicPropertiesBuilder {
+    if priority > PRIORITY_MAX {
+      return self.invalid(format!("priority {} is not between 0 and {}", priority, PRIORITY_MAX));
+    }
+    self.properties.priority = Some(priority);
+    self
+  }
+
+  pub fn correlation_id(self, correlation_id: &str) -> BasicPropertiesBuilder {
+    self.short_string("correlation_id", correlation_id, basic::Properties::with_correlation_id)
+  }
+
+  pub fn reply_to(self, reply_to: &str) -> BasicPropertiesBuilder {
+    self.short_string("reply_to", reply_to, basic::Properties::with_reply_to)
+  }
+
+  /// time after which the server discards the message, sent in milliseconds
+  ///
+  /// fractions of a millisecond are rounded up, so that a short expiration does not become 0
+  pub fn expiration(mut self, expiration: Duration) -> BasicPropertiesBuilder {
+    let rounding = expiration.subsec_nanos() > expiration.subsec_millis() * 1_000_000;
+    let subsec_millis = u64::from(expiration.subsec_millis()) + u64::from(rounding);
+    let millis = expiration.as_secs().checked_mul(1000).and_then(|ms| ms.checked_add(subsec_millis));
+    match millis {
+      Some(millis) => {
+        self.properties.expiration = Some(millis.to_string());
+        self
+      },
+      None         => self.invalid(format!("expiration {:?} does not fit in milliseconds", expiration)),
+    }
+  }
+
+  pub fn message_id(self, message_id: &str) -> BasicPropertiesBuilder {
+    self.short_string("message_id", message_id, basic::Properties::with_message_id)
+  }
+
+  /// creation time of the message, sent in seconds since the UNIX epoch
+  pub fn timestamp(mut self, timestamp: SystemTime) -> BasicPropertiesBuilder {
+    match timestamp.duration_since(UNIX_EPOCH) {
+      Ok(since_epoch) => {
+        self.properties.timestamp = Some(since_epoch.as_secs());
+        self
+      },
+      Err(_)          => self.invalid(format!("timestamp {:?} is before the UNIX epoch", timestamp)),
+    }
+  }
+
+  /// application specific type of the message
+  pub fn message_type(self, message_type: &str) -> BasicPropertiesBuilder {
+    self.short_string("type", message_type, basic::Properties::with_type_)
+  }
+
+  /// RabbitMQ checks that it is the user of the connection
+  pub fn user_id(self, user_id: &str) -> BasicPropertiesBuilder {
+    self.short_string("user_id", user_id, basic::Properties::with_user_id)
+  }
+
+  pub fn app_id(self, app_id: &str) -> BasicPropertiesBuilder {
+    self.short_string("app_id", app_id, basic::Properties::with_app_id)
+  }
+
+  /// returns the properties, or the first invalid value
+  pub fn build(self) -> Result<basic::Properties, Error> {
+    match self.error {
+      Some(error) => Err(error),
+      None        => Ok(self.properties),
+    }
+  }
+}
+
+/// `type/subtype`, optionally followed by `; parameters`
+fn is_mime_type(value: &str) -> bool {
+  let is_token = |s: &str| !s.is_empty() && s.bytes().all(|c| c.is_ascii_alphanumeric() || b"!#$&^_.+-".contains(&c));
+  let essence = value.split(';').next().unwrap_or("").trim();
+  let mut parts = essence.splitn(2, '/');
+  match (parts.next(), parts.next()) {
+    (Some(type_), Some(subtype)) => is_token(type_) && is_token(subtype),
+    _                            => false,
+  }
+}
+
+/// checks the limits of the values nested in a header
+fn check_value(value: &AMQPValue) -> Option<String> {
+  match *value {
+    AMQPValue::LongString(ref s) if s.len() as u64 > LONG_STRING_MAX => Some(format!("string of {} bytes is too long", s.len())),
+    AMQPValue::ByteArray(ref a)  if a.len() as u64 > LONG_STRING_MAX => Some(format!("byte array of {} bytes is too long", a.len())),
+    AMQPValue::FieldArray(ref values) => values.iter().filter_map(check_value).next(),
+    AMQPValue::FieldTable(ref table)  => table.iter().filter_map(|(key, value)| {
+      if key.len() > SHORT_STRING_MAX {
+        Some(format!("field name {:?} is {} bytes long, the limit is {}", key, key.len(), SHORT_STRING_MAX))
+      } else {
+        check_value(value)
+      }
+    }).next(),
+    _ => None,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn builds_typed_properties() {
+    let properties = BasicPropertiesBuilder::new()
+      .content_type("application/json; charset=utf-8")
+      .delivery_mode(DeliveryMode::Persistent)
+      .priority(9)
+      .expiration(Duration::from_millis(1500))
+      .timestamp(UNIX_EPOCH + Duration::from_secs(42))
+      .header("retries", 3u32)
+      .header("origin", "test")
+      .build()
+      .unwrap();
+
+    assert_eq!(properties.content_type, Some("application/json; charset=utf-8".to_string()));
+    assert_eq!(properties.delivery_mode, Some(2));
+    assert_eq!(properties.priority, Some(9));
+    assert_eq!(properties.expiration, Some("1500".to_string()));
+    assert_eq!(properties.timestamp, Some(42));
+    let headers = properties.headers.unwrap();
+    assert_eq!(headers.get("retries"), Some(&AMQPValue::LongUInt(3)));
+    assert_eq!(headers.get("origin"), Some(&AMQPValue::LongString("test".to_string())));
+  }
+
+  #[test]
+  fn rounds_expiration_up() {
+    let expiration = |duration: Duration| BasicPropertiesBuilder::new().expiration(duration).build().unwrap().expiration;
+
+    assert_eq!(expiration(Duration::from_millis(1500)), Some("1500".to_string()));
+    assert_eq!(expiration(Duration::from_micros(1_500_200)), Some("1501".to_string()));
+    assert_eq!(expiration(Duration::from_micros(500)), Some("1".to_string()));
+    assert_eq!(expiration(Duration::from_secs(0)), Some("0".to_string()));
+  }
+
+  #[test]
+  fn rejects_invalid_values() {
+    let invalid = |builder: BasicPropertiesBuilder| match builder.build() {
+      Err(Error::InvalidProperty(message)) => message,
+      r                                    => panic!("expected an invalid property, got {:?}", r),
+    };
+
+    assert_eq!(invalid(BasicPropertiesBuilder::new().priority(10)), "priority 10 is not between 0 and 9");
+    assert_eq!(invalid(BasicPropertiesBuilder::new().content_type("json")), "content_type \"json\" is not a MIME type");
+    assert_eq!(invalid(BasicPropertiesBuilder::new().message_id(&"x".repeat(256))), "message_id is 256 bytes long, the limit is 255");
+    assert!(invalid(BasicPropertiesBuilder::new().timestamp(UNIX_EPOCH - Duration::from_secs(1))).contains("before the UNIX epoch"));
+    assert!(invalid(BasicPropertiesBuilder::new().header(&"k".repeat(256), true)).starts_with("header name"));
+    assert!(invalid(BasicPropertiesBuilder::new().expiration(Duration::from_secs(u64::MAX))).contains("does not fit in milliseconds"));
+
+    // the values nested in tables and arrays are checked too
+    let mut table = FieldTable::new();
+    table.insert("k".repeat(256), AMQPValue::Boolean(true));
+    assert_eq!(invalid(BasicPropertiesBuilder::new().header("table", table.clone())),
+      format!("header \"table\": field name {:?} is 256 bytes long, the limit is 255", "k".repeat(256)));
+    let array = vec![AMQPValue::LongUInt(1), AMQPValue::FieldTable(table)];
+    assert!(invalid(BasicPropertiesBuilder::new().header("array", array)).starts_with("header \"array\": field name"));
+
+    // the first error is kept
+    assert_eq!(invalid(BasicPropertiesBuilder::new().priority(10).content_type("json")), "priority 10 is not between 0 and 9");
+  }
+}
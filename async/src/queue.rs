@@ -29,6 +29,8 @@ pub struct Consumer {
   pub nowait:          bool,
   pub messages:        VecDeque<Delivery>,
   pub current_message: Option<Delivery>,
+  /// the server sent basic.cancel for this consumer
+  pub cancelled:       bool,
 }
 
 #[derive(Clone,Debug,PartialEq)]
@@ -56,7 +58,30 @@ impl Queue {
   }
 
   pub fn next_delivery(&mut self, consumer_tag: &str) -> Option<Delivery> {
-    self.consumers.get_mut(consumer_tag).and_then(|consumer| consumer.messages.pop_front())
+    let (delivery, drained) = match self.consumers.get_mut(consumer_tag) {
+      Some(consumer) => {
+        let delivery = consumer.messages.pop_front();
+        (delivery, consumer.cancelled && consumer.messages.is_empty())
+      },
+      None           => (None, false),
+    };
+    if drained {
+      self.consumers.remove(consumer_tag);
+    }
+    delivery
+  }
+
+  /// handles a consumer cancelled by the server
+  ///
+  /// the consumer is removed once the messages it already received are consumed
+  pub fn cancel_consumer(&mut self, consumer_tag: &str) {
+    let drained = self.consumers.get_mut(consumer_tag).map(|consumer| {
+      consumer.cancelled = true;
+      consumer.messages.is_empty()
+    }).unwrap_or(false);
+    if drained {
+      self.consumers.remove(consumer_tag);
+    }
   }
 
   pub fn next_basic_get_message(&mut self) -> Option<BasicGetMessage> {
@@ -81,7 +81,7 @@ fn connection() {
 
       println!("will publish");
       conn.basic_publish(channel_a, 0, "".to_string(), "hello-async".to_string(), false, false).expect("basic_publish");
-      let payload = b"Hello world!";
+      let payload = &b"Hello world!"[..];
       conn.send_content_frames(channel_a, 60, payload, basic::Properties::default());
       println!("[{}] state: {:?}", line!(), conn.run(&mut stream, &mut send_buffer, &mut receive_buffer).unwrap());
       thread::sleep(time::Duration::from_millis(100));
@@ -90,5 +90,5 @@ fn connection() {
       println!("received message: {:?}", msg);
       println!("data: {}", std::str::from_utf8(&msg.data).unwrap());
 
-      assert_eq!(msg.data, b"Hello world!");
+      assert_eq!(msg.data, &b"Hello world!"[..]);
 }
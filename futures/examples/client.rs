@@ -10,6 +10,7 @@ use tokio::net::TcpStream;
 use tokio::runtime::Runtime;
 use lapin::types::FieldTable;
 use lapin::client::ConnectionOptions;
+use lapin::error::Error;
 use lapin::channel::{BasicConsumeOptions,BasicGetOptions,BasicPublishOptions,BasicProperties,ConfirmSelectOptions,ExchangeBindOptions,ExchangeUnbindOptions,ExchangeDeclareOptions,ExchangeDeleteOptions,QueueBindOptions,QueueDeclareOptions};
 
 fn main() {
@@ -18,7 +19,7 @@ fn main() {
   let addr = std::env::var("AMQP_ADDR").unwrap_or_else(|_| "127.0.0.1:5672".to_string()).parse().unwrap();
 
   Runtime::new().unwrap().block_on(
-    TcpStream::connect(&addr).and_then(|stream| {
+    TcpStream::connect(&addr).map_err(Error::from).and_then(|stream| {
       lapin::client::Client::connect(stream, ConnectionOptions {
         frame_max: 65535,
         ..Default::default()
@@ -38,7 +39,7 @@ fn main() {
               channel.basic_publish(
                 "hello_exchange",
                 "hello_2",
-                b"hello from tokio",
+                b"hello from tokio".to_vec(),
                 BasicPublishOptions::default(),
                 BasicProperties::default().with_user_id("guest".to_string()).with_reply_to("foobar".to_string())
               ).map(|confirmation| {
@@ -14,6 +14,7 @@ use tokio::net::TcpStream;
 use tokio::runtime::Runtime;
 use lapin::types::FieldTable;
 use lapin::client::{Client, ConnectionOptions};
+use lapin::error::Error;
 use lapin::channel::{BasicConsumeOptions, BasicProperties, BasicPublishOptions, ConfirmSelectOptions, QueueDeclareOptions};
 
 const N_CONSUMERS : u8 = 8;
@@ -47,18 +48,18 @@ fn main() {
     // let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
 
     runtime.block_on(
-        TcpStream::connect(&addr).and_then(|stream| {
+        TcpStream::connect(&addr).map_err(Error::from).and_then(|stream| {
             Client::connect(stream, ConnectionOptions {
                 frame_max: 65535,
                 ..Default::default()
             })
         }).and_then(|(client, heartbeat)| {
             tokio::spawn(heartbeat.map_err(|e| eprintln!("heartbeat error: {:?}", e)))
-                .into_future().map(|_| client).map_err(|_| io::Error::new(io::ErrorKind::Other, "spawn error"))
+                .into_future().map(|_| client).map_err(|_| Error::from(io::Error::new(io::ErrorKind::Other, "spawn error")))
         }).and_then(|client| {
             let _client = client.clone();
             futures::stream::iter_ok(0..N_CONSUMERS).for_each(move |n| tokio::spawn(create_consumer(&_client, n)))
-                .into_future().map(move |_| client).map_err(|_| io::Error::new(io::ErrorKind::Other, "spawn error"))
+                .into_future().map(move |_| client).map_err(|_| Error::from(io::Error::new(io::ErrorKind::Other, "spawn error")))
         }).and_then(|client| {
             client.create_confirm_channel(ConfirmSelectOptions::default()).and_then(move |channel| {
                 futures::stream::iter_ok((0..N_CONSUMERS).flat_map(|c| {
@@ -71,7 +72,7 @@ fn main() {
                     info!("will publish {}", message);
 
                     channel.queue_declare(&queue, QueueDeclareOptions::default(), FieldTable::new()).and_then(move |_| {
-                        channel.basic_publish("", &queue, message.as_str().as_bytes(), BasicPublishOptions::default(), BasicProperties::default()).map(move |confirmation| {
+                        channel.basic_publish("", &queue, message, BasicPublishOptions::default(), BasicProperties::default()).map(move |confirmation| {
                             println!("got confirmation (consumer {}, message {}): {:?}", c, m, confirmation);
                         })
                     })
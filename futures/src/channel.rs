@@ -1,23 +1,31 @@
-use std::io::{self,Error,ErrorKind};
+use std::fmt;
+use bytes::Bytes;
+use std::io;
 use futures::{Async,Future,future,Poll,Stream,task};
 use tokio_io::{AsyncRead,AsyncWrite};
 use std::sync::{Arc,Mutex};
+use std::time::{Duration,Instant};
+use tokio_timer::Delay;
 use lapin_async;
 use lapin_async::api::{ChannelState, RequestId};
 use lapin_async::connection::Connection;
 use lapin_async::generated::basic;
 
+use error::Error;
 use transport::*;
 use message::BasicGetMessage;
 use types::FieldTable;
 use consumer::Consumer;
+use returned::ReturnedMessages;
 use queue::Queue;
 
 /// `Channel` provides methods to act on a channel, such as managing queues
 //#[derive(Clone)]
 pub struct Channel<T> {
   pub transport: Arc<Mutex<AMQPTransport<T>>>,
-  pub id:    u16,
+  pub id:        u16,
+  /// serial of the channel, see `Connection::get_serial`
+  pub serial:    u64,
 }
 
 impl<T> Clone for Channel<T>
@@ -26,6 +34,7 @@ impl<T> Clone for Channel<T>
     Channel {
       transport: self.transport.clone(),
       id:        self.id,
+      serial:    self.serial,
     }
   }
 }
@@ -102,12 +111,31 @@ pub struct QueuePurgeOptions {
 
 #[derive(Clone,Debug,Default,PartialEq)]
 pub struct BasicPublishOptions {
-  pub ticket:    u16,
-  pub mandatory: bool,
-  pub immediate: bool,
+  pub ticket:          u16,
+  pub mandatory:       bool,
+  pub immediate:       bool,
+  /// how long to wait for the server to unblock the connection or to restart the
+  /// channel flow before failing, forever if `None`
+  pub blocked_timeout: Option<Duration>,
 }
 
 pub type BasicProperties = basic::Properties;
+pub use lapin_async::properties::{BasicPropertiesBuilder,DeliveryMode,HeaderValue};
+
+/// answer of the server to a message published with `basic_publish`
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Confirmation {
+  /// the channel is not in confirm mode
+  NotRequested,
+  /// the message was ack'd, with its delivery tag
+  Ack(u64),
+  /// the message was nack'd, with its delivery tag
+  Nack(u64),
+  /// the mandatory or immediate message could not be delivered and was sent back
+  ///
+  /// the message itself is available from the stream returned by `Channel::returned_messages`
+  Returned(u64),
+}
 
 #[derive(Clone,Debug,Default,PartialEq)]
 pub struct BasicConsumeOptions {
@@ -146,21 +174,22 @@ pub struct ChannelFlowOptions {
 
 impl<T: AsyncRead+AsyncWrite+Send+'static> Channel<T> {
     /// create a channel
-    pub fn create(transport: Arc<Mutex<AMQPTransport<T>>>) -> impl Future<Item = Self, Error = io::Error> + Send + 'static {
+    pub fn create(transport: Arc<Mutex<AMQPTransport<T>>>) -> impl Future<Item = Self, Error = Error> + Send + 'static {
         let channel_transport = transport.clone();
 
         future::poll_fn(move || {
             let mut transport = lock_transport!(channel_transport);
-            if let Some(id) = transport.conn.create_channel() {
-                return Ok(Async::Ready(Channel {
+            match transport.conn.create_channel() {
+                Ok(id) => Ok(Async::Ready(Channel {
                     id,
+                    serial:    transport.conn.get_serial(id).unwrap_or(0),
                     transport: channel_transport.clone(),
-                }))
-            } else {
-                return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "The maximum number of channels for this connection has been reached"));
+                })),
+                Err(e) => Err(io::Error::new(io::ErrorKind::ConnectionAborted, e)),
             }
         }).and_then(|channel| {
             let channel_id = channel.id;
+            let serial = channel.serial;
             channel.run_on_locked_transport("create", "Could not create channel", move |transport| {
                 transport.conn.channel_open(channel_id, "".to_string()).map(Some)
             }).and_then(move |_| {
@@ -169,8 +198,9 @@ impl<T: AsyncRead+AsyncWrite+Send+'static> Channel<T> {
 
                     match transport.conn.get_state(channel_id) {
                         Some(ChannelState::Connected) => return Ok(Async::Ready(())),
-                        Some(ChannelState::Error)     => return Err(io::Error::new(io::ErrorKind::Other, format!("Failed to open channel"))),
-                        Some(ChannelState::Closed)    => return Err(io::Error::new(io::ErrorKind::Other, format!("Failed to open channel"))),
+                        Some(ChannelState::Error)     => return Err(open_failed(channel_id)),
+                        // channels closed by the server are removed from the connection
+                        Some(ChannelState::Closed) | None => return Err(transport.channel_close_error(channel_id, serial).unwrap_or_else(|| open_failed(channel_id))),
                         _                             => {
                             task::current().notify();
                             return Ok(Async::NotReady);
@@ -180,26 +210,26 @@ impl<T: AsyncRead+AsyncWrite+Send+'static> Channel<T> {
             }).map(move |_| {
                 channel
             })
-        })
+        }).map_err(Error::from)
     }
 
     /// request access
     ///
     /// returns a future that resolves once the access is granted
-    pub fn access_request(&self, realm: &str, options: AccessRequestOptions) -> impl Future<Item = (), Error = io::Error> + Send + 'static {
+    pub fn access_request(&self, realm: &str, options: AccessRequestOptions) -> impl Future<Item = (), Error = Error> + Send + 'static {
         let channel_id = self.id;
         let realm = realm.to_string();
 
         self.run_on_locked_transport("access_request", "Could not request access", move |transport| {
             transport.conn.access_request(channel_id, realm,
                 options.exclusive, options.passive, options.active, options.write, options.read).map(Some)
-        }).map(|_| ())
+        }).map(|_| ()).map_err(Error::from)
     }
 
     /// declares an exchange
     ///
     /// returns a future that resolves once the exchange is available
-    pub fn exchange_declare(&self, name: &str, exchange_type: &str, options: ExchangeDeclareOptions, arguments: FieldTable) -> impl Future<Item = (), Error = io::Error> + Send + 'static {
+    pub fn exchange_declare(&self, name: &str, exchange_type: &str, options: ExchangeDeclareOptions, arguments: FieldTable) -> impl Future<Item = (), Error = Error> + Send + 'static {
         let channel_id = self.id;
         let name = name.to_string();
         let exchange_type = exchange_type.to_string();
@@ -207,26 +237,26 @@ impl<T: AsyncRead+AsyncWrite+Send+'static> Channel<T> {
         self.run_on_locked_transport("exchange_declare", "Could not declare exchange", move |transport| {
             transport.conn.exchange_declare(channel_id, options.ticket, name, exchange_type,
                 options.passive, options.durable, options.auto_delete, options.internal, options.nowait, arguments).map(Some)
-        }).map(|_| ())
+        }).map(|_| ()).map_err(Error::from)
     }
 
     /// deletes an exchange
     ///
     /// returns a future that resolves once the exchange is deleted
-    pub fn exchange_delete(&self, name: &str, options: ExchangeDeleteOptions) -> impl Future<Item = (), Error = io::Error> + Send + 'static {
+    pub fn exchange_delete(&self, name: &str, options: ExchangeDeleteOptions) -> impl Future<Item = (), Error = Error> + Send + 'static {
         let channel_id = self.id;
         let name = name.to_string();
 
         self.run_on_locked_transport("exchange_delete", "Could not delete exchange", move |transport| {
             transport.conn.exchange_delete(channel_id, options.ticket, name,
                 options.if_unused, options.nowait).map(Some)
-        }).map(|_| ())
+        }).map(|_| ()).map_err(Error::from)
     }
 
     /// binds an exchange to another exchange
     ///
     /// returns a future that resolves once the exchanges are bound
-    pub fn exchange_bind(&self, destination: &str, source: &str, routing_key: &str, options: ExchangeBindOptions, arguments: FieldTable) -> impl Future<Item = (), Error = io::Error> + Send + 'static {
+    pub fn exchange_bind(&self, destination: &str, source: &str, routing_key: &str, options: ExchangeBindOptions, arguments: FieldTable) -> impl Future<Item = (), Error = Error> + Send + 'static {
         let channel_id = self.id;
         let destination = destination.to_string();
         let source = source.to_string();
@@ -235,13 +265,13 @@ impl<T: AsyncRead+AsyncWrite+Send+'static> Channel<T> {
         self.run_on_locked_transport("exchange_bind", "Could not bind exchange", move |transport| {
             transport.conn.exchange_bind(channel_id, options.ticket, destination, source, routing_key,
                 options.nowait, arguments).map(Some)
-        }).map(|_| ())
+        }).map(|_| ()).map_err(Error::from)
     }
 
     /// unbinds an exchange from another one
     ///
     /// returns a future that resolves once the exchanges are unbound
-    pub fn exchange_unbind(&self, destination: &str, source: &str, routing_key: &str, options: ExchangeUnbindOptions, arguments: FieldTable) -> impl Future<Item = (), Error = io::Error> + Send + 'static {
+    pub fn exchange_unbind(&self, destination: &str, source: &str, routing_key: &str, options: ExchangeUnbindOptions, arguments: FieldTable) -> impl Future<Item = (), Error = Error> + Send + 'static {
         let channel_id = self.id;
         let destination = destination.to_string();
         let source = source.to_string();
@@ -250,7 +280,7 @@ impl<T: AsyncRead+AsyncWrite+Send+'static> Channel<T> {
         self.run_on_locked_transport("exchange_unbind", "Could not unbind exchange", move |transport| {
             transport.conn.exchange_unbind(channel_id, options.ticket, destination, source, routing_key,
                 options.nowait, arguments).map(Some)
-        }).map(|_| ())
+        }).map(|_| ()).map_err(Error::from)
     }
 
     /// declares a queue
@@ -259,7 +289,7 @@ impl<T: AsyncRead+AsyncWrite+Send+'static> Channel<T> {
     ///
     /// the `mandatory` and `ìmmediate` options can be set to true,
     /// but the return message will not be handled
-    pub fn queue_declare(&self, name: &str, options: QueueDeclareOptions, arguments: FieldTable) -> impl Future<Item = Queue, Error = io::Error> + Send + 'static {
+    pub fn queue_declare(&self, name: &str, options: QueueDeclareOptions, arguments: FieldTable) -> impl Future<Item = Queue, Error = Error> + Send + 'static {
         let channel_id = self.id;
         let name = name.to_string();
         let transport = self.transport.clone();
@@ -277,13 +307,13 @@ impl<T: AsyncRead+AsyncWrite+Send+'static> Channel<T> {
                 return Ok(Async::NotReady)
               }
             })
-        }).map(Queue::new)
+        }).map(Queue::new).map_err(Error::from)
     }
 
     /// binds a queue to an exchange
     ///
     /// returns a future that resolves once the queue is bound to the exchange
-    pub fn queue_bind(&self, name: &str, exchange: &str, routing_key: &str, options: QueueBindOptions, arguments: FieldTable) -> impl Future<Item = (), Error = io::Error> + Send + 'static {
+    pub fn queue_bind(&self, name: &str, exchange: &str, routing_key: &str, options: QueueBindOptions, arguments: FieldTable) -> impl Future<Item = (), Error = Error> + Send + 'static {
         let channel_id = self.id;
         let name = name.to_string();
         let exchange = exchange.to_string();
@@ -292,13 +322,13 @@ impl<T: AsyncRead+AsyncWrite+Send+'static> Channel<T> {
         self.run_on_locked_transport("queue_bind", "Could not bind queue", move |transport| {
             transport.conn.queue_bind(channel_id, options.ticket, name, exchange, routing_key,
                 options.nowait, arguments).map(Some)
-        }).map(|_| ())
+        }).map(|_| ()).map_err(Error::from)
     }
 
     /// unbinds a queue from the exchange
     ///
     /// returns a future that resolves once the queue is unbound from the exchange
-    pub fn queue_unbind(&self, name: &str, exchange: &str, routing_key: &str, options: QueueUnbindOptions, arguments: FieldTable) -> impl Future<Item = (), Error = io::Error> + Send + 'static {
+    pub fn queue_unbind(&self, name: &str, exchange: &str, routing_key: &str, options: QueueUnbindOptions, arguments: FieldTable) -> impl Future<Item = (), Error = Error> + Send + 'static {
         let channel_id = self.id;
         let name = name.to_string();
         let exchange = exchange.to_string();
@@ -306,47 +336,101 @@ impl<T: AsyncRead+AsyncWrite+Send+'static> Channel<T> {
 
         self.run_on_locked_transport("queue_unbind", "Could not unbind queue from the exchange", move |transport| {
             transport.conn.queue_unbind(channel_id, options.ticket, name, exchange, routing_key, arguments).map(Some)
-        }).map(|_| ())
+        }).map(|_| ()).map_err(Error::from)
     }
 
     /// sets up confirm extension for this channel
-    pub fn confirm_select(&self, options: ConfirmSelectOptions) -> impl Future<Item = (), Error = io::Error> + Send + 'static {
+    pub fn confirm_select(&self, options: ConfirmSelectOptions) -> impl Future<Item = (), Error = Error> + Send + 'static {
         let channel_id = self.id;
 
         self.run_on_locked_transport("confirm_select", "Could not activate confirm extension", move |transport| {
             transport.conn.confirm_select(channel_id, options.nowait).map(Some)
-        }).map(|_| ())
+        }).map(|_| ()).map_err(Error::from)
+    }
+
+    /// puts the channel in transaction mode
+    ///
+    /// publishes, acks and rejects are then only applied by `tx_commit`.
+    /// The channel cannot be in confirm mode at the same time
+    pub fn tx_select(&self) -> impl Future<Item = (), Error = Error> + Send + 'static {
+        let channel_id = self.id;
+
+        self.run_on_locked_transport("tx_select", "Could not start transaction mode", move |transport| {
+            transport.conn.tx_select(channel_id).map(Some)
+        }).map(|_| ()).map_err(Error::from)
+    }
+
+    /// commits the messages published and acknowledged since the last commit or rollback
+    pub fn tx_commit(&self) -> impl Future<Item = (), Error = Error> + Send + 'static {
+        let channel_id = self.id;
+
+        self.run_on_locked_transport("tx_commit", "Could not commit transaction", move |transport| {
+            transport.conn.tx_commit(channel_id).map(Some)
+        }).map(|_| ()).map_err(Error::from)
+    }
+
+    /// discards the messages published and acknowledged since the last commit or rollback
+    pub fn tx_rollback(&self) -> impl Future<Item = (), Error = Error> + Send + 'static {
+        let channel_id = self.id;
+
+        self.run_on_locked_transport("tx_rollback", "Could not rollback transaction", move |transport| {
+            transport.conn.tx_rollback(channel_id).map(Some)
+        }).map(|_| ()).map_err(Error::from)
     }
 
     /// specifies quality of service for a channel
-    pub fn basic_qos(&self, options: BasicQosOptions) -> impl Future<Item = (), Error = io::Error> + Send + 'static {
+    pub fn basic_qos(&self, options: BasicQosOptions) -> impl Future<Item = (), Error = Error> + Send + 'static {
         let channel_id = self.id;
 
         self.run_on_locked_transport("basic_qos", "Could not setup qos", move |transport| {
             transport.conn.basic_qos(channel_id, options.prefetch_size, options.prefetch_count, options.global).map(|_| None)
-        }).map(|_| ())
+        }).map(|_| ()).map_err(Error::from)
     }
 
     /// publishes a message on a queue
     ///
     /// the future's result is:
-    /// - `Some(request_id)` if we're on a confirm channel and the message was ack'd
-    /// - `None` if we're not on a confirm channel or the message was nack'd
-    pub fn basic_publish(&self, exchange: &str, routing_key: &str, payload: &[u8], options: BasicPublishOptions, properties: BasicProperties) -> impl Future<Item = Option<RequestId>, Error = io::Error> + Send + 'static {
+    /// - `Confirmation::Ack(delivery_tag)` if we're on a confirm channel and the message was ack'd
+    /// - `Confirmation::Nack(delivery_tag)` if the message was nack'd
+    /// - `Confirmation::Returned(delivery_tag)` if the message was returned by the server
+    /// - `Confirmation::NotRequested` if we're not on a confirm channel
+    ///
+    /// if the server blocked the connection or stopped the channel flow, the message is only
+    /// sent once publishing is allowed again. The future fails if that takes longer than
+    /// `options.blocked_timeout`
+    ///
+    /// the payload can be anything convertible to `Bytes` that owns its data or is `'static`,
+    /// like a `Vec<u8>`, a `String` or a `&'static [u8]`. It is converted once, then split in
+    /// frames by slicing it
+    pub fn basic_publish<P: Into<Bytes>>(&self, exchange: &str, routing_key: &str, payload: P, options: BasicPublishOptions, properties: BasicProperties) -> impl Future<Item = Confirmation, Error = Error> + Send + 'static {
+        let payload = payload.into();
+        let channel = self.clone();
         let channel_id = self.id;
+        let serial = self.serial;
         let exchange = exchange.to_string();
         let routing_key = routing_key.to_string();
 
-        self.run_on_locked_transport_full("basic_publish", "Could not publish", move |transport| {
+        self.wait_publish_allowed(options.blocked_timeout).and_then(move |_| channel.run_on_locked_transport_full("basic_publish", "Could not publish", move |transport| {
             transport.conn.basic_publish(channel_id, options.ticket, exchange, routing_key,
                 options.mandatory, options.immediate).map(Some)
         }, move |conn, delivery_tag| {
+            if conn.get_serial(channel_id) != Some(serial) {
+                let err = conn.get_closed_channel_reason(channel_id, serial)
+                              .map(lapin_async::error::Error::ChannelClosed)
+                              .unwrap_or(lapin_async::error::Error::InvalidChannel);
+                return Err(io::Error::new(io::ErrorKind::Other, err));
+            }
             conn.channels.get_mut(&channel_id).and_then(|c| {
                 if c.confirm {
                     if c.acked.remove(&delivery_tag) {
-                        Some(Ok(Async::Ready(Some(delivery_tag))))
+                        if c.returned.remove(&delivery_tag) {
+                            Some(Ok(Async::Ready(Some(Confirmation::Returned(delivery_tag)))))
+                        } else {
+                            Some(Ok(Async::Ready(Some(Confirmation::Ack(delivery_tag)))))
+                        }
                     } else if c.nacked.remove(&delivery_tag) {
-                        Some(Ok(Async::Ready(None)))
+                        c.returned.remove(&delivery_tag);
+                        Some(Ok(Async::Ready(Some(Confirmation::Nack(delivery_tag)))))
                     } else {
                         info!("message with tag {} still in unacked: {:?}", delivery_tag, c.unacked);
                         task::current().notify();
@@ -355,8 +439,56 @@ impl<T: AsyncRead+AsyncWrite+Send+'static> Channel<T> {
                 } else {
                     None
                 }
-            }).unwrap_or(Ok(Async::Ready(None)))
-        }, Some((payload.to_vec(), properties)))
+            }).unwrap_or(Ok(Async::Ready(Some(Confirmation::NotRequested))))
+        }, Some((payload, properties))).map(|confirmation| confirmation.unwrap_or(Confirmation::NotRequested))).map_err(Error::from)
+    }
+
+    /// creates a stream of the messages the server sent back with basic.return
+    ///
+    /// this happens for `mandatory` messages that could not be routed to a queue,
+    /// and `immediate` messages that could not be delivered to a consumer
+    pub fn returned_messages(&self) -> ReturnedMessages<T> {
+        ReturnedMessages {
+            transport:  self.transport.clone(),
+            channel_id: self.id,
+            serial:     self.serial,
+            listener:   None,
+        }
+    }
+
+    /// resolves once the server neither blocks the connection nor stopped the channel flow
+    fn wait_publish_allowed(&self, timeout: Option<Duration>) -> impl Future<Item = (), Error = io::Error> + Send + 'static {
+        let channel_id = self.id;
+        let transport = self.transport.clone();
+        let mut delay = timeout.map(|timeout| Delay::new(Instant::now() + timeout));
+
+        future::poll_fn(move || {
+            let mut transport = lock_transport!(transport);
+            let send_flow = |transport: &AMQPTransport<T>| {
+                transport.conn.channels.get(&channel_id).map(|c| c.send_flow).unwrap_or(true)
+            };
+            if !transport.conn.is_blocked() && send_flow(&transport) {
+                return Ok(Async::Ready(()));
+            }
+
+            transport.poll()?;
+            let reason = if let Some(ref reason) = transport.conn.blocked {
+                format!("connection blocked by the server: {}", reason)
+            } else if !send_flow(&transport) {
+                "channel flow stopped by the server".to_string()
+            } else {
+                return Ok(Async::Ready(()));
+            };
+
+            if let Some(ref mut delay) = delay {
+                if delay.poll().map_err(|e| io::Error::new(io::ErrorKind::Other, e))?.is_ready() {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, reason));
+                }
+            }
+            trace!("waiting for the server to allow publishing: {}", reason);
+            task::current().notify();
+            Ok(Async::NotReady)
+        })
     }
 
     /// creates a consumer stream
@@ -365,7 +497,7 @@ impl<T: AsyncRead+AsyncWrite+Send+'static> Channel<T> {
     ///
     /// `Consumer` implements `futures::Stream`, so it can be used with any of
     /// the usual combinators
-    pub fn basic_consume(&self, queue: &Queue, consumer_tag: &str, options: BasicConsumeOptions, arguments: FieldTable) -> impl Future<Item = Consumer<T>, Error = io::Error> + Send + 'static {
+    pub fn basic_consume(&self, queue: &Queue, consumer_tag: &str, options: BasicConsumeOptions, arguments: FieldTable) -> impl Future<Item = Consumer<T>, Error = Error> + Send + 'static {
         let channel_id = self.id;
         let transport = self.transport.clone();
         let consumer_tag = consumer_tag.to_string();
@@ -373,6 +505,7 @@ impl<T: AsyncRead+AsyncWrite+Send+'static> Channel<T> {
         let mut consumer = Consumer {
             transport:    self.transport.clone(),
             channel_id:   self.id,
+            serial:       self.serial,
             queue:        queue.name(),
             consumer_tag: consumer_tag.to_string(),
             registered:   false,
@@ -395,41 +528,43 @@ impl<T: AsyncRead+AsyncWrite+Send+'static> Channel<T> {
             trace!("basic_consume received response, returning consumer");
             consumer.consumer_tag = consumer_tag;
             consumer
-        })
+        }).map_err(Error::from)
     }
 
     /// acks a message
-    pub fn basic_ack(&self, delivery_tag: u64) -> impl Future<Item = (), Error = io::Error> + Send + 'static {
+    pub fn basic_ack(&self, delivery_tag: u64) -> impl Future<Item = (), Error = Error> + Send + 'static {
         let channel_id = self.id;
 
         self.run_on_locked_transport("basic_ack", "Could not ack message", move |transport| {
             transport.conn.basic_ack(channel_id, delivery_tag, false).map(|_| None)
-        }).map(|_| ())
+        }).map(|_| ()).map_err(Error::from)
     }
 
     /// nacks a message
-    pub fn basic_nack(&self, delivery_tag: u64, requeue: bool) -> impl Future<Item = (), Error = io::Error> + Send + 'static {
+    pub fn basic_nack(&self, delivery_tag: u64, requeue: bool) -> impl Future<Item = (), Error = Error> + Send + 'static {
         let channel_id = self.id;
 
         self.run_on_locked_transport("basic_nack", "Could not nack message", move |transport| {
             transport.conn.basic_nack(channel_id, delivery_tag, false, requeue).map(|_| None)
-        }).map(|_| ())
+        }).map(|_| ()).map_err(Error::from)
     }
 
     /// rejects a message
-    pub fn basic_reject(&self, delivery_tag: u64, requeue: bool) -> impl Future<Item = (), Error = io::Error> + Send + 'static {
+    pub fn basic_reject(&self, delivery_tag: u64, requeue: bool) -> impl Future<Item = (), Error = Error> + Send + 'static {
         let channel_id = self.id;
 
         self.run_on_locked_transport("basic_reject", "Could not reject message", move |transport| {
             transport.conn.basic_reject(channel_id, delivery_tag, requeue).map(|_| None)
-        }).map(|_| ())
+        }).map(|_| ()).map_err(Error::from)
     }
 
     /// gets a message
-    pub fn basic_get(&self, queue: &str, options: BasicGetOptions) -> impl Future<Item = BasicGetMessage, Error = io::Error> + Send + 'static {
+    pub fn basic_get(&self, queue: &str, options: BasicGetOptions) -> impl Future<Item = BasicGetMessage, Error = Error> + Send + 'static {
         let channel_id = self.id;
+        let serial = self.serial;
         let _queue = queue.to_string();
         let queue = queue.to_string();
+        let empty_queue = queue.clone();
         let receive_transport = self.transport.clone();
         let receive_future = future::poll_fn(move || {
             let mut transport = lock_transport!(receive_transport);
@@ -437,36 +572,39 @@ impl<T: AsyncRead+AsyncWrite+Send+'static> Channel<T> {
             if let Some(message) = transport.conn.next_basic_get_message(channel_id, &_queue) {
                 return Ok(Async::Ready(message));
             }
+            if let Some(err) = transport.channel_error(channel_id, serial) {
+                return Err(err);
+            }
             Ok(Async::NotReady)
         });
 
         self.run_on_locked_transport_full("basic_get", "Could not get message", move |transport| {
             transport.conn.basic_get(channel_id, options.ticket, queue, options.no_ack).map(Some)
-        }, |conn, request_id| {
+        }, move |conn, request_id| {
             match conn.finished_get_result(request_id) {
                 Some(answer) => if answer {
                     Ok(Async::Ready(Some(request_id)))
                 } else {
-                    Err(Error::new(ErrorKind::Other, "basic get returned empty"))
+                    Err(io::Error::new(io::ErrorKind::Other, lapin_async::error::Error::EmptyQueue(empty_queue.clone())))
                 },
                 None         => {
                     task::current().notify();
                     Ok(Async::NotReady)
                 }
             }
-        }, None).and_then(|_| receive_future)
+        }, None).and_then(|_| receive_future).map_err(Error::from)
     }
 
     /// Purge a queue.
     ///
     /// This method removes all messages from a queue which are not awaiting acknowledgment.
-    pub fn queue_purge(&self, queue_name: &str, options: QueuePurgeOptions) -> impl Future<Item = (), Error = io::Error> + Send + 'static {
+    pub fn queue_purge(&self, queue_name: &str, options: QueuePurgeOptions) -> impl Future<Item = (), Error = Error> + Send + 'static {
         let channel_id = self.id;
         let queue_name = queue_name.to_string();
 
         self.run_on_locked_transport("queue_purge", "Could not purge queue", move |transport| {
             transport.conn.queue_purge(channel_id, options.ticket, queue_name, options.nowait).map(Some)
-        }).map(|_| ())
+        }).map(|_| ()).map_err(Error::from)
     }
 
     /// Delete a queue.
@@ -478,57 +616,59 @@ impl<T: AsyncRead+AsyncWrite+Send+'static> Channel<T> {
     /// If the queue has consumers the server does not delete it but raises a channel exception instead.
     ///
     /// If `if_empty` is set, the server will only delete the queue if it has no messages.
-    pub fn queue_delete(&self, queue_name: &str, options: QueueDeleteOptions) -> impl Future<Item = (), Error = io::Error> + Send + 'static {
+    pub fn queue_delete(&self, queue_name: &str, options: QueueDeleteOptions) -> impl Future<Item = (), Error = Error> + Send + 'static {
         let channel_id = self.id;
         let queue_name = queue_name.to_string();
 
         self.run_on_locked_transport("queue_purge", "Could not purge queue", move |transport| {
             transport.conn.queue_delete(channel_id, options.ticket, queue_name, options.if_unused, options.if_empty, options.no_wait).map(Some)
-        }).map(|_| ())
+        }).map(|_| ()).map_err(Error::from)
     }
 
     /// closes the channel
-    pub fn close(&self, code: u16, message: &str) -> impl Future<Item = (), Error = io::Error> + Send + 'static {
+    pub fn close(&self, code: u16, message: &str) -> impl Future<Item = (), Error = Error> + Send + 'static {
         let channel_id = self.id;
         let message = message.to_string();
 
         self.run_on_locked_transport("close", "Could not close channel", move |transport| {
             transport.conn.channel_close(channel_id, code, message, 0, 0).map(|_| None)
-        }).map(|_| ())
+        }).map(|_| ()).map_err(Error::from)
     }
 
     /// ack a channel close
-    pub fn close_ok(&self) -> impl Future<Item = (), Error = io::Error> + Send + 'static {
+    pub fn close_ok(&self) -> impl Future<Item = (), Error = Error> + Send + 'static {
         let channel_id = self.id;
 
         self.run_on_locked_transport("close_ok", "Could not ack closed channel", move |transport| {
             transport.conn.channel_close_ok(channel_id).map(|_| None)
-        }).map(|_| ())
+        }).map(|_| ()).map_err(Error::from)
     }
 
     /// update a channel flow
-    pub fn channel_flow(&self, options: ChannelFlowOptions) -> impl Future<Item = (), Error=io::Error> + Send + 'static {
+    pub fn channel_flow(&self, options: ChannelFlowOptions) -> impl Future<Item = (), Error = Error> + Send + 'static {
         let channel_id = self.id;
 
         self.run_on_locked_transport("channel_flow", "Could not update channel flow", move |transport| {
             transport.conn.channel_flow(channel_id, options.active).map(|_| None)
-        }).map(|_| ())
+        }).map(|_| ()).map_err(Error::from)
     }
 
     /// ack an update to a channel flow
-    pub fn channel_flow_ok(&self, options: ChannelFlowOptions) -> impl Future<Item = (), Error=io::Error> + Send + 'static {
+    pub fn channel_flow_ok(&self, options: ChannelFlowOptions) -> impl Future<Item = (), Error = Error> + Send + 'static {
         let channel_id = self.id;
 
         self.run_on_locked_transport("channel_flow_ok", "Could not ack update to channel flow", move |transport| {
             transport.conn.channel_flow_ok(channel_id, options.active).map(|_| None)
-        }).map(|_| ())
+        }).map(|_| ()).map_err(Error::from)
     }
 
-    fn run_on_locked_transport_full<Action, Finished>(&self, method: &str, error: &str, action: Action, finished: Finished, payload: Option<(Vec<u8>, BasicProperties)>) -> impl Future<Item = Option<RequestId>, Error = io::Error> + Send + 'static
+    fn run_on_locked_transport_full<Action, Finished, R>(&self, method: &str, error: &str, action: Action, finished: Finished, payload: Option<(Bytes, BasicProperties)>) -> impl Future<Item = Option<R>, Error = io::Error> + Send + 'static
         where Action:   'static + Send + FnOnce(&mut AMQPTransport<T>) -> Result<Option<RequestId>, lapin_async::error::Error>,
-              Finished: 'static + Send + Fn(&mut Connection, RequestId) -> Poll<Option<RequestId>, io::Error> {
+              Finished: 'static + Send + Fn(&mut Connection, RequestId) -> Poll<Option<R>, io::Error>,
+              R:        'static + Send + fmt::Debug {
         trace!("run on locked transport; method={:?}", method);
         let channel_id = self.id;
+        let serial = self.serial;
         let transport = self.transport.clone();
         let _transport = self.transport.clone();
         let _method = method.to_string();
@@ -547,13 +687,16 @@ impl<T: AsyncRead+AsyncWrite+Send+'static> Channel<T> {
             // This is needed because we're in an FnMut and thus cannot transfer ownership as an
             // FnMut can be called several time and action which is an FnOnce can only be called
             // once (which is implemented as a ownership transfer).
+            if let Some(err) = transport.channel_error(channel_id, serial) {
+                return Err(err);
+            }
             match action.take().unwrap()(&mut transport) {
-                Err(e)         => Err(Error::new(ErrorKind::Other, format!("{}: {:?}", error, e))),
+                Err(e)         => Err(transport.channel_error(channel_id, serial).unwrap_or_else(|| Error::from(e).context(&error).into())),
                 Ok(request_id) => {
                     trace!("run on locked transport; method={:?} request_id={:?}", _method, request_id);
 
                     if let Some((payload, mut properties)) = payload.take().unwrap() {
-                        transport.send_content_frames(channel_id, payload.as_slice(), properties);
+                        transport.send_content_frames(channel_id, payload, properties);
                     }
 
                     Ok(Async::Ready(request_id))
@@ -568,7 +711,13 @@ impl<T: AsyncRead+AsyncWrite+Send+'static> Channel<T> {
                 let mut transport = lock_transport!(_transport);
 
                 if let Some(request_id) = request_id {
-                    Self::wait_for_answer(&mut transport, request_id, &finished)
+                    let answer = Self::wait_for_answer(&mut transport, request_id, &finished)?;
+                    if answer.is_not_ready() {
+                        if let Some(err) = transport.channel_error(channel_id, serial) {
+                            return Err(err);
+                        }
+                    }
+                    Ok(answer)
                 } else {
                     transport.poll().map(|r| r.map(|_| None))
                 }
@@ -592,8 +741,9 @@ impl<T: AsyncRead+AsyncWrite+Send+'static> Channel<T> {
     }
 
     /// internal method to wait until a request succeeds
-    pub fn wait_for_answer<Finished>(tr: &mut AMQPTransport<T>, request_id: RequestId, finished: &Finished) -> Poll<Option<RequestId>, io::Error>
-        where Finished: 'static + Send + Fn(&mut Connection, RequestId) -> Poll<Option<RequestId>, io::Error> {
+    pub fn wait_for_answer<Finished, R>(tr: &mut AMQPTransport<T>, request_id: RequestId, finished: &Finished) -> Poll<Option<R>, io::Error>
+        where Finished: 'static + Send + Fn(&mut Connection, RequestId) -> Poll<Option<R>, io::Error>,
+              R:        fmt::Debug {
             trace!("wait for answer; request_id={:?}", request_id);
             tr.poll()?;
             trace!("wait for answer transport poll; request_id={:?} status=NotReady", request_id);
@@ -606,3 +756,8 @@ impl<T: AsyncRead+AsyncWrite+Send+'static> Channel<T> {
             Ok(Async::NotReady)
     }
 }
+
+/// error of a channel that went to the error state or was removed while opening
+fn open_failed(channel_id: u16) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, lapin_async::error::Error::ChannelOpenFailed(channel_id))
+}
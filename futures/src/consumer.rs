@@ -4,12 +4,18 @@ use tokio_io::{AsyncRead,AsyncWrite};
 use std::sync::{Arc,Mutex};
 
 use message::Delivery;
+use error::Error;
 use transport::*;
 
+/// stream of the messages delivered to a consumer
+///
+/// the stream ends once the consumer is cancelled, by the client or by the server
 #[derive(Clone)]
 pub struct Consumer<T> {
   pub transport:    Arc<Mutex<AMQPTransport<T>>>,
   pub channel_id:   u16,
+  /// serial of the channel, see `Connection::get_serial`
+  pub serial:       u64,
   pub queue:        String,
   pub consumer_tag: String,
   pub registered:   bool,
@@ -17,21 +23,34 @@ pub struct Consumer<T> {
 
 impl<T: AsyncRead+AsyncWrite+Sync+Send+'static> Stream for Consumer<T> {
   type Item = Delivery;
-  type Error = io::Error;
+  type Error = Error;
 
-  fn poll(&mut self) -> Poll<Option<Delivery>, io::Error> {
+  fn poll(&mut self) -> Poll<Option<Delivery>, Error> {
+    self.poll_next().map_err(Error::from)
+  }
+}
+
+impl<T: AsyncRead+AsyncWrite+Sync+Send+'static> Consumer<T> {
+  fn poll_next(&mut self) -> Poll<Option<Delivery>, io::Error> {
     trace!("poll; consumer_tag={:?}", self.consumer_tag);
     let mut transport = lock_transport!(self.transport);
     if !self.registered {
-        transport.register_consumer(&self.consumer_tag, task::current());
+        transport.register_consumer(self.channel_id, &self.consumer_tag, task::current());
         self.registered = true;
     }
     transport.poll()?;
     trace!("poll transport; consumer_tag={:?} status=NotReady", self.consumer_tag);
+    if let Some(err) = transport.channel_error(self.channel_id, self.serial) {
+      return Err(err);
+    }
     if let Some(message) = transport.conn.next_delivery(self.channel_id, &self.queue, &self.consumer_tag) {
       trace!("delivery; consumer_tag={:?} delivery_tag={:?}", self.consumer_tag, message.delivery_tag);
       return Ok(Async::Ready(Some(message)));
     }
+    if !transport.conn.has_consumer(self.channel_id, &self.queue, &self.consumer_tag) {
+      trace!("consumer cancelled; consumer_tag={:?}", self.consumer_tag);
+      return Ok(Async::Ready(None));
+    }
     Ok(Async::NotReady)
   }
 }
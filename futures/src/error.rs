@@ -0,0 +1,324 @@
+use std::fmt;
+use std::io;
+use std::error::Error as StdError;
+use amq_protocol::protocol::{AMQPError, AMQPHardError, AMQPSoftError};
+use lapin_async;
+use lapin_async::connection::CloseReason;
+
+/// error returned by the futures and streams of this crate
+///
+/// besides the cause of the failure, it exposes the AMQP reply code sent by the
+/// server (or implied by a protocol violation), the method that failed, and
+/// whether the error closed the channel (soft) or the whole connection (hard)
+#[derive(Debug)]
+pub struct Error {
+  kind:    ErrorKind,
+  context: Option<String>,
+}
+
+/// the different causes of an `Error`
+#[derive(Debug)]
+pub enum ErrorKind {
+  /// the server closed the channel with this reason
+  ChannelClosed(CloseReason),
+  /// the server closed the connection with this reason
+  ConnectionClosed(CloseReason),
+  /// the client state machine refused the operation or failed to parse the server's answer
+  Protocol(lapin_async::error::Error),
+  /// the underlying stream failed, or a timeout expired
+  Io(io::Error),
+}
+
+impl Error {
+  pub fn new(kind: ErrorKind) -> Error {
+    Error { kind, context: None }
+  }
+
+  /// adds a description of the operation that failed, displayed before the cause
+  pub fn context(mut self, context: &str) -> Error {
+    self.context = Some(context.to_string());
+    self
+  }
+
+  pub fn kind(&self) -> &ErrorKind {
+    &self.kind
+  }
+
+  pub fn into_kind(self) -> ErrorKind {
+    self.kind
+  }
+
+  fn close_reason(&self) -> Option<&CloseReason> {
+    match self.kind {
+      ErrorKind::ChannelClosed(ref reason) | ErrorKind::ConnectionClosed(ref reason) => Some(reason),
+      _                                                                             => None,
+    }
+  }
+
+  /// AMQP reply code, as sent by the server with channel.close or connection.close
+  ///
+  /// frames rejected by the client are reported with the code it sent in connection.close
+  pub fn reply_code(&self) -> Option<u16> {
+    match self.kind {
+      ErrorKind::ChannelClosed(ref reason) | ErrorKind::ConnectionClosed(ref reason) => Some(reason.reply_code),
+      ErrorKind::Protocol(ref e)                                                     => e.hard_error().map(|e| e.get_id()),
+      _                                                                             => None,
+    }
+  }
+
+  /// reply code mapped to the error constants of the specification, if it is one of them
+  pub fn amqp_error(&self) -> Option<AMQPError> {
+    self.reply_code().and_then(AMQPError::from_id)
+  }
+
+  pub fn reply_text(&self) -> Option<&str> {
+    self.close_reason().map(|reason| reason.reply_text.as_str())
+  }
+
+  /// class of the method that caused the server to close the channel or connection
+  pub fn class_id(&self) -> Option<u16> {
+    self.close_reason().map(|reason| reason.class_id).filter(|id| *id != 0)
+  }
+
+  /// method that caused the server to close the channel or connection
+  pub fn method_id(&self) -> Option<u16> {
+    self.close_reason().map(|reason| reason.method_id).filter(|id| *id != 0)
+  }
+
+  /// the channel was closed, the other channels of the connection can still be used
+  pub fn is_soft(&self) -> bool {
+    match self.kind {
+      ErrorKind::ChannelClosed(_) => true,
+      _                           => false,
+    }
+  }
+
+  /// the connection was closed or cannot be used anymore
+  ///
+  /// io errors only count when they come from the connection itself, like a reset or invalid data
+  pub fn is_hard(&self) -> bool {
+    match self.kind {
+      ErrorKind::ChannelClosed(_)    => false,
+      ErrorKind::ConnectionClosed(_) => true,
+      ErrorKind::Protocol(ref e)     => match *e {
+        lapin_async::error::Error::ParserError                    |
+        lapin_async::error::Error::NoSupportedSASLMechanism(_)    |
+        lapin_async::error::Error::SASLError(_)                   |
+        lapin_async::error::Error::NoSupportedLocale(_)           |
+        lapin_async::error::Error::MissedHeartbeats               |
+        lapin_async::error::Error::UnsupportedProtocolVersion(_)  |
+        lapin_async::error::Error::NotAMQPServer                  => true,
+        _                                                         => e.hard_error().is_some(),
+      },
+      ErrorKind::Io(ref e)           => match e.kind() {
+        io::ErrorKind::ConnectionRefused |
+        io::ErrorKind::ConnectionReset   |
+        io::ErrorKind::ConnectionAborted |
+        io::ErrorKind::NotConnected      |
+        io::ErrorKind::BrokenPipe        |
+        io::ErrorKind::UnexpectedEof     |
+        io::ErrorKind::InvalidData       => true,
+        _                                => false,
+      },
+    }
+  }
+
+  /// the same operation could succeed later, on this channel or a new one
+  ///
+  /// this covers transient server conditions (a forced close, a lack of resources,
+  /// a locked queue), lost connections and timeouts, but not refused or invalid operations
+  pub fn is_retryable(&self) -> bool {
+    match self.kind {
+      ErrorKind::ChannelClosed(_) | ErrorKind::ConnectionClosed(_) => match self.amqp_error() {
+        Some(AMQPError::Soft(AMQPSoftError::RESOURCELOCKED))   |
+        Some(AMQPError::Hard(AMQPHardError::CONNECTIONFORCED)) |
+        Some(AMQPError::Hard(AMQPHardError::RESOURCEERROR))    |
+        Some(AMQPError::Hard(AMQPHardError::INTERNALERROR))    => true,
+        _                                                      => false,
+      },
+      ErrorKind::Protocol(ref e) => match *e {
+        lapin_async::error::Error::NotConnected           |
+        lapin_async::error::Error::MissedHeartbeats       |
+        lapin_async::error::Error::ChannelLimitReached(_) |
+        lapin_async::error::Error::FlowStopped            => true,
+        _                                                 => false,
+      },
+      ErrorKind::Io(ref e) => match e.kind() {
+        io::ErrorKind::TimedOut          |
+        io::ErrorKind::Interrupted       |
+        io::ErrorKind::WouldBlock        |
+        io::ErrorKind::ConnectionRefused |
+        io::ErrorKind::ConnectionReset   |
+        io::ErrorKind::ConnectionAborted |
+        io::ErrorKind::BrokenPipe        |
+        io::ErrorKind::UnexpectedEof     => true,
+        _                                => false,
+      },
+    }
+  }
+}
+
+impl fmt::Display for ErrorKind {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      ErrorKind::ChannelClosed(ref reason) => write!(f, "channel closed by server: {} {} (class {}, method {})",
+                                                reason.reply_code, reason.reply_text, reason.class_id, reason.method_id),
+      ErrorKind::ConnectionClosed(ref reason) => write!(f, "{}", reason),
+      ErrorKind::Protocol(ref e)              => write!(f, "{}", e),
+      ErrorKind::Io(ref e)                    => write!(f, "{}", e),
+    }
+  }
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self.context {
+      Some(ref context) => write!(f, "{}: {}", context, self.kind),
+      None              => write!(f, "{}", self.kind),
+    }
+  }
+}
+
+impl StdError for Error {
+  fn source(&self) -> Option<&(dyn StdError + 'static)> {
+    match self.kind {
+      ErrorKind::ChannelClosed(_)          => None,
+      ErrorKind::ConnectionClosed(ref r)   => Some(r),
+      ErrorKind::Protocol(ref e)           => Some(e),
+      ErrorKind::Io(ref e)                 => Some(e),
+    }
+  }
+}
+
+impl From<ErrorKind> for Error {
+  fn from(kind: ErrorKind) -> Error {
+    Error::new(kind)
+  }
+}
+
+impl From<lapin_async::error::Error> for Error {
+  fn from(error: lapin_async::error::Error) -> Error {
+    match error {
+      lapin_async::error::Error::ChannelClosed(reason) => Error::new(ErrorKind::ChannelClosed(reason)),
+      e                                                => Error::new(ErrorKind::Protocol(e)),
+    }
+  }
+}
+
+/// the transport reports failures as `io::Error`, wrapping a `CloseReason`
+/// when the server closed the connection, and a `lapin_async::error::Error`
+/// for protocol failures: those are unwrapped to their own kind
+impl From<io::Error> for Error {
+  fn from(error: io::Error) -> Error {
+    let wrapped = {
+      let inner = error.get_ref();
+      inner.map(|e| e.is::<Error>()).unwrap_or(false)
+        || inner.map(|e| e.is::<CloseReason>()).unwrap_or(false)
+        || inner.map(|e| e.is::<lapin_async::error::Error>()).unwrap_or(false)
+    };
+    if !wrapped {
+      return Error::new(ErrorKind::Io(error));
+    }
+
+    let inner = error.into_inner().expect("checked that the error wraps another one");
+    let inner = match inner.downcast::<Error>() {
+      Ok(e)      => return *e,
+      Err(inner) => inner,
+    };
+    match inner.downcast::<CloseReason>() {
+      Ok(reason) => Error::new(ErrorKind::ConnectionClosed(*reason)),
+      Err(inner) => Error::from(*inner.downcast::<lapin_async::error::Error>().expect("checked the type of the wrapped error")),
+    }
+  }
+}
+
+/// allows mixing these errors with the ones of the underlying stream
+impl From<Error> for io::Error {
+  fn from(error: Error) -> io::Error {
+    match error.kind {
+      ErrorKind::Io(e) if error.context.is_none() => e,
+      _                                          => io::Error::new(io::ErrorKind::Other, error),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn reason(reply_code: u16) -> CloseReason {
+    CloseReason {
+      reply_code,
+      reply_text: "TEXT".to_string(),
+      class_id:   50,
+      method_id:  10,
+    }
+  }
+
+  #[test]
+  fn unwraps_transport_errors() {
+    let err = Error::from(io::Error::new(io::ErrorKind::ConnectionAborted, reason(320)));
+    assert!(err.is_hard());
+    assert!(!err.is_soft());
+    assert!(err.is_retryable());
+    assert_eq!(err.reply_code(), Some(320));
+
+    let err = Error::from(io::Error::new(io::ErrorKind::Other, lapin_async::error::Error::ChannelClosed(reason(404))));
+    assert!(err.is_soft());
+    assert!(!err.is_hard());
+    assert!(!err.is_retryable());
+    assert_eq!(err.reply_code(), Some(404));
+    assert_eq!(err.reply_text(), Some("TEXT"));
+    assert_eq!(err.class_id(), Some(50));
+    assert_eq!(err.method_id(), Some(10));
+    match err.amqp_error() {
+      Some(AMQPError::Soft(AMQPSoftError::NOTFOUND)) => {},
+      _                                              => panic!("expected NOT_FOUND"),
+    }
+
+    let err = Error::from(io::Error::new(io::ErrorKind::InvalidData, lapin_async::error::Error::FrameTooLarge(4097, 4096)));
+    assert!(err.is_hard());
+    assert_eq!(err.reply_code(), Some(501));
+
+    let err = Error::from(io::Error::new(io::ErrorKind::InvalidData, lapin_async::error::Error::UnexpectedFrame("content body on channel 0".to_string())));
+    assert!(err.is_hard());
+    assert_eq!(err.reply_code(), Some(505));
+
+    let err = Error::from(io::Error::new(io::ErrorKind::TimedOut, "blocked"));
+    assert!(!err.is_hard());
+    assert!(err.is_retryable());
+    assert_eq!(err.reply_code(), None);
+
+    let err = Error::from(io::Error::new(io::ErrorKind::Other, lapin_async::error::Error::EmptyQueue("jobs".to_string())));
+    match *err.kind() {
+      ErrorKind::Protocol(lapin_async::error::Error::EmptyQueue(ref queue)) => assert_eq!(queue, "jobs"),
+      ref kind                                                             => panic!("expected an empty queue, got {:?}", kind),
+    }
+    assert!(!err.is_hard());
+
+    assert!(!Error::from(io::Error::new(io::ErrorKind::Other, "custom stream failure")).is_hard());
+    assert!(Error::from(io::Error::new(io::ErrorKind::ConnectionReset, "reset")).is_hard());
+  }
+
+  #[test]
+  fn keeps_context_through_io_errors() {
+    let err = Error::from(lapin_async::error::Error::FlowStopped).context("Could not publish");
+    let err = Error::from(io::Error::from(err));
+    assert!(err.is_retryable());
+    assert!(!err.is_soft());
+    assert!(!err.is_hard());
+    assert_eq!(err.to_string(), "Could not publish: the server stopped the flow of messages on this channel");
+  }
+
+  #[test]
+  fn channel_errors_are_not_hard() {
+    // the async API also returns NotConnected for a channel that is closed or not opened yet
+    let err = Error::from(io::Error::from(Error::from(lapin_async::error::Error::NotConnected).context("failed to handle frame")));
+    match *err.kind() {
+      ErrorKind::Protocol(lapin_async::error::Error::NotConnected) => {},
+      ref kind                                                      => panic!("expected NotConnected, got {:?}", kind),
+    }
+    assert!(!err.is_hard());
+    assert!(err.is_retryable());
+  }
+}
@@ -0,0 +1,58 @@
+use std::io;
+use futures::{Async,Poll,Stream,task};
+use lapin_async::connection::ConnectionState;
+use tokio_io::{AsyncRead,AsyncWrite};
+use std::sync::{Arc,Mutex};
+
+use error::Error;
+use transport::*;
+
+pub use lapin_async::connection::ConnectionEvent;
+
+/// stream of the changes of the connection's state, like the server blocking publishes
+///
+/// the events received while no stream is polled are dropped. Only the last created
+/// stream is notified, and it stops listening once dropped
+pub struct ConnectionEvents<T> {
+  pub transport: Arc<Mutex<AMQPTransport<T>>>,
+  /// id given by `AMQPTransport::register_events` on the first poll
+  pub listener:  Option<u64>,
+}
+
+impl<T: AsyncRead+AsyncWrite+Sync+Send+'static> Stream for ConnectionEvents<T> {
+  type Item = ConnectionEvent;
+  type Error = Error;
+
+  fn poll(&mut self) -> Poll<Option<ConnectionEvent>, Error> {
+    self.poll_next().map_err(Error::from)
+  }
+}
+
+impl<T: AsyncRead+AsyncWrite+Sync+Send+'static> ConnectionEvents<T> {
+  fn poll_next(&mut self) -> Poll<Option<ConnectionEvent>, io::Error> {
+    trace!("poll; connection events");
+    let mut transport = lock_transport!(self.transport);
+    if self.listener.is_none() {
+        self.listener = Some(transport.register_events(task::current()));
+    }
+    transport.poll()?;
+    if let Some(event) = transport.conn.next_event() {
+      trace!("connection event; event={:?}", event);
+      return Ok(Async::Ready(Some(event)));
+    }
+    if transport.conn.state == ConnectionState::Closed {
+      return Ok(Async::Ready(None));
+    }
+    Ok(Async::NotReady)
+  }
+}
+
+impl<T> Drop for ConnectionEvents<T> {
+  fn drop(&mut self) {
+    if let Some(listener) = self.listener {
+      if let Ok(mut transport) = self.transport.lock() {
+        transport.unregister_events(listener);
+      }
+    }
+  }
+}
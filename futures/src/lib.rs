@@ -25,6 +25,7 @@
 //! use futures::Stream;
 //! use tokio::net::TcpStream;
 //! use lapin::client::ConnectionOptions;
+//! use lapin::error::Error;
 //! use lapin::channel::{BasicPublishOptions,BasicProperties,QueueDeclareOptions};
 //! use lapin::types::FieldTable;
 //!
@@ -32,7 +33,7 @@
 //!   let addr = "127.0.0.1:5672".parse().unwrap();
 //!
 //!   tokio::run(
-//!     TcpStream::connect(&addr).and_then(|stream| {
+//!     TcpStream::connect(&addr).map_err(Error::from).and_then(|stream| {
 //!
 //!       // connect() returns a future of an AMQP Client
 //!       // that resolves once the handshake is done
@@ -52,7 +53,7 @@
 //!       channel.queue_declare("hello", QueueDeclareOptions::default(), FieldTable::new()).and_then(move |_| {
 //!         info!("channel {} declared queue {}", id, "hello");
 //!
-//!         channel.basic_publish("", "hello", b"hello from tokio", BasicPublishOptions::default(), BasicProperties::default())
+//!         channel.basic_publish("", "hello", b"hello from tokio".to_vec(), BasicPublishOptions::default(), BasicProperties::default())
 //!       })
 //!     }).map(|_| ()).map_err(|_| ())
 //!   )
@@ -71,6 +72,7 @@
 //! use futures::Stream;
 //! use tokio::net::TcpStream;
 //! use lapin::client::ConnectionOptions;
+//! use lapin::error::Error;
 //! use lapin::channel::{BasicConsumeOptions,BasicPublishOptions,QueueDeclareOptions};
 //! use lapin::types::FieldTable;
 //!
@@ -78,7 +80,7 @@
 //!   let addr = "127.0.0.1:5672".parse().unwrap();
 //!
 //!   tokio::run(
-//!     TcpStream::connect(&addr).and_then(|stream| {
+//!     TcpStream::connect(&addr).map_err(Error::from).and_then(|stream| {
 //!
 //!       // connect() returns a future of an AMQP Client
 //!       // that resolves once the handshake is done
@@ -128,13 +130,17 @@ extern crate futures;
 extern crate lapin_async;
 #[macro_use] extern crate log;
 extern crate nom;
+extern crate tokio_executor;
 extern crate tokio_io;
 extern crate tokio_timer;
 
 #[macro_use] pub mod transport;
 pub mod client;
+pub mod error;
 pub mod channel;
 pub mod consumer;
+pub mod returned;
+pub mod events;
 pub mod queue;
 pub mod message;
 pub mod types;
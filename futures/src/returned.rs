@@ -0,0 +1,59 @@
+use std::io;
+use futures::{Async,Poll,Stream,task};
+use tokio_io::{AsyncRead,AsyncWrite};
+use std::sync::{Arc,Mutex};
+
+use message::BasicReturnMessage;
+use error::Error;
+use transport::*;
+
+/// stream of the messages sent back by the server on a channel
+///
+/// the messages returned while no stream is polled are dropped. Only the last created
+/// stream of a channel is notified, and it stops listening once dropped
+pub struct ReturnedMessages<T> {
+  pub transport:  Arc<Mutex<AMQPTransport<T>>>,
+  pub channel_id: u16,
+  /// serial of the channel, see `Connection::get_serial`
+  pub serial:     u64,
+  /// id given by `AMQPTransport::register_returns` on the first poll
+  pub listener:   Option<u64>,
+}
+
+impl<T: AsyncRead+AsyncWrite+Sync+Send+'static> Stream for ReturnedMessages<T> {
+  type Item = BasicReturnMessage;
+  type Error = Error;
+
+  fn poll(&mut self) -> Poll<Option<BasicReturnMessage>, Error> {
+    self.poll_next().map_err(Error::from)
+  }
+}
+
+impl<T: AsyncRead+AsyncWrite+Sync+Send+'static> ReturnedMessages<T> {
+  fn poll_next(&mut self) -> Poll<Option<BasicReturnMessage>, io::Error> {
+    trace!("poll; returned messages on channel {}", self.channel_id);
+    let mut transport = lock_transport!(self.transport);
+    if self.listener.is_none() {
+        self.listener = Some(transport.register_returns(self.channel_id, task::current()));
+    }
+    transport.poll()?;
+    if let Some(err) = transport.channel_error(self.channel_id, self.serial) {
+      return Err(err);
+    }
+    if let Some(message) = transport.conn.next_returned_message(self.channel_id) {
+      trace!("returned message; channel={} reply_code={} reply_text={:?}", self.channel_id, message.reply_code, message.reply_text);
+      return Ok(Async::Ready(Some(message)));
+    }
+    Ok(Async::NotReady)
+  }
+}
+
+impl<T> Drop for ReturnedMessages<T> {
+  fn drop(&mut self) {
+    if let Some(listener) = self.listener {
+      if let Ok(mut transport) = self.transport.lock() {
+        transport.unregister_returns(self.channel_id, listener);
+      }
+    }
+  }
+}
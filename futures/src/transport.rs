@@ -1,14 +1,17 @@
 /// low level wrapper for the state machine, encoding and decoding from lapin-async
+use lapin_async;
 use lapin_async::connection::*;
 use lapin_async::format::frame::*;
 
-use nom::{IResult,Offset};
+use nom::IResult;
 use cookie_factory::GenError;
-use bytes::{BufMut, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 use std::cmp;
 use std::collections::HashMap;
 use std::iter::repeat;
 use std::io::{self,Error,ErrorKind};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize,Ordering};
 use futures::{Async,AsyncSink,Poll,Sink,StartSend,Stream,Future,future,task};
 use tokio_io::{AsyncRead,AsyncWrite};
 use tokio_io::codec::{Decoder,Encoder,Framed};
@@ -17,7 +20,12 @@ use client::ConnectionOptions;
 
 /// implements tokio-io's Decoder and Encoder
 pub struct AMQPCodec {
-    pub frame_max: u32,
+    pub frame_max:         u32,
+    /// negotiated frame_max, checked on inbound frames. Until the handshake is done,
+    /// or if it is 0, the minimum frame size of the specification is enforced
+    pub inbound_frame_max: Arc<AtomicUsize>,
+    /// true until the first frame is decoded, the server can then answer with a protocol header
+    pub handshake:         bool,
 }
 
 impl Decoder for AMQPCodec {
@@ -25,21 +33,54 @@ impl Decoder for AMQPCodec {
     type Error = io::Error;
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Frame>, io::Error> {
-        let (consumed, f) = match frame(buf) {
-          IResult::Incomplete(_) => {
-            return Ok(None)
-          },
-          IResult::Error(e) => {
-            return Err(io::Error::new(io::ErrorKind::Other, format!("parse error: {:?}", e)))
+        let header = match frame_header(buf) {
+          IResult::Incomplete(_) => return Ok(None),
+          IResult::Error(_) => {
+            if !self.handshake {
+              return Err(io::Error::new(io::ErrorKind::InvalidData, lapin_async::error::Error::UnknownFrameType(buf[0])));
+            }
+            // a server that does not support our version answers with its own protocol header
+            return match protocol_version(buf) {
+              IResult::Incomplete(_)    => Ok(None),
+              IResult::Done(_, version) => Err(io::Error::new(io::ErrorKind::InvalidData, lapin_async::error::Error::UnsupportedProtocolVersion(version))),
+              IResult::Error(_)         => Err(io::Error::new(io::ErrorKind::InvalidData, lapin_async::error::Error::NotAMQPServer)),
+            }
           },
-          IResult::Done(i, frame) => {
-            (buf.offset(i), frame)
+          IResult::Done(_, header) => header,
+        };
+
+        // check the announced size before reserving anything for it
+        let frame_max = match self.inbound_frame_max.load(Ordering::Relaxed) as u32 {
+          0         => FRAME_MIN_SIZE,
+          frame_max => frame_max,
+        };
+        if let Some(size) = frame_size(buf) {
+          if size > frame_max {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, lapin_async::error::Error::FrameTooLarge(size, frame_max)));
           }
+        }
+
+        let size = header.frame_size();
+        if buf.len() < size {
+          // make room for the whole frame at once, and wait for it before parsing the payload
+          let additional = size - buf.len();
+          buf.reserve(additional);
+          return Ok(None);
+        }
+
+        let data = buf.split_to(size).freeze();
+        let f = match frame_payload(&data, &header) {
+          // keep the payload as a slice of the read buffer instead of copying it
+          IResult::Done(_, _) if header.frame_type == FrameType::Body => Frame::Body(header.channel_id, data.slice(7, size - 1)),
+          IResult::Done(_, payload) => match parse_payload(payload, &header) {
+            IResult::Done(_, f) => f,
+            _                   => return Err(io::Error::new(io::ErrorKind::InvalidData, lapin_async::error::Error::InvalidFramePayload(header.channel_id))),
+          },
+          _ => return Err(io::Error::new(io::ErrorKind::InvalidData, lapin_async::error::Error::InvalidFrameEnd(data[size - 1]))),
         };
 
         trace!("amqp decoder; frame={:?}", f);
-
-        buf.split_to(consumed);
+        self.handshake = false;
 
         Ok(Some(f))
     }
@@ -103,9 +144,17 @@ impl Encoder for AMQPCodec {
 
 /// Wrappers over a `Framed` stream using `AMQPCodec` and lapin-async's `Connection`
 pub struct AMQPTransport<T> {
-  upstream:  Framed<T,AMQPCodec>,
-  consumers: HashMap<String, task::Task>,
-  pub conn:  Connection,
+  upstream:          Framed<T,AMQPCodec>,
+  /// consumer tasks by consumer tag, with the channel of the consumer
+  consumers:         HashMap<String, (u16, task::Task)>,
+  /// tasks of the streams of returned messages by channel, with their listener id
+  returns:           HashMap<u16, (u64, task::Task)>,
+  /// task of the stream of connection events, with its listener id
+  events:            Option<(u64, task::Task)>,
+  /// id given to the last registered listener
+  listener_index:    u64,
+  inbound_frame_max: Arc<AtomicUsize>,
+  pub conn:          Connection,
 }
 
 impl<T> AMQPTransport<T>
@@ -119,6 +168,9 @@ impl<T> AMQPTransport<T>
   pub fn connect(stream: T, options: ConnectionOptions) -> impl Future<Item = AMQPTransport<T>, Error = io::Error> + Send + 'static {
     let mut conn = Connection::new();
     conn.set_credentials(&options.username, &options.password);
+    conn.set_auth_mechanisms(&options.auth_mechanisms);
+    conn.set_locales(&options.locales);
+    conn.set_client_properties(options.client_properties);
     conn.set_vhost(&options.vhost);
     conn.set_frame_max(options.frame_max);
     conn.set_heartbeat(options.heartbeat);
@@ -127,13 +179,20 @@ impl<T> AMQPTransport<T>
       let err = format!("Failed to connect: {:?}", e);
       Error::new(ErrorKind::ConnectionAborted, err)
     }).and_then(|_| {
+        let inbound_frame_max = Arc::new(AtomicUsize::new(FRAME_MIN_SIZE as usize));
         let codec = AMQPCodec {
-          frame_max: conn.configuration.frame_max,
+          frame_max:         conn.configuration.frame_max,
+          inbound_frame_max: inbound_frame_max.clone(),
+          handshake:         true,
         };
         let t = AMQPTransport {
-          upstream:     stream.framed(codec),
-          consumers:    HashMap::new(),
-          conn:         conn,
+          upstream:          stream.framed(codec),
+          consumers:         HashMap::new(),
+          returns:           HashMap::new(),
+          events:            None,
+          listener_index:    0,
+          inbound_frame_max,
+          conn:              conn,
         };
 
         AMQPTransportConnector {
@@ -158,15 +217,39 @@ impl<T> AMQPTransport<T>
   ///
   /// This function only appends the frames to a queue, to actually send the frames you have to
   /// call either `poll` or `poll_send`.
-  pub fn send_content_frames(&mut self, channel_id: u16, payload: &[u8], properties: BasicProperties) {
+  pub fn send_content_frames(&mut self, channel_id: u16, payload: Bytes, properties: BasicProperties) {
     self.conn.send_content_frames(channel_id, 60, payload, properties);
   }
 
-  fn maybe_notify_consumers(&self) {
+  fn maybe_notify_consumers(&mut self) {
     if self.conn.has_pending_deliveries() {
-      for t in self.consumers.values() {
+      for (_, t) in self.consumers.values() {
+        t.notify();
+      }
+    }
+    // cancelled consumers have to see that their stream ended
+    let conn = &self.conn;
+    self.consumers.retain(|consumer_tag, &mut (channel_id, ref t)| {
+      let active = conn.has_consumer_tag(channel_id, consumer_tag);
+      if !active {
         t.notify();
       }
+      active
+    });
+    let returns = &self.returns;
+    for channel in self.conn.channels.values_mut().filter(|c| !c.returns.is_empty()) {
+      match returns.get(&channel.id) {
+        Some(listener) => listener.1.notify(),
+        // nobody listens, the returned messages would pile up for the whole channel
+        None           => channel.returns.clear(),
+      }
+    }
+    if !self.conn.events.is_empty() {
+      match self.events {
+        Some((_, ref t)) => t.notify(),
+        // nobody listens, the events would pile up for the whole connection
+        None             => self.conn.events.clear(),
+      }
     }
   }
 
@@ -185,8 +268,12 @@ impl<T> AMQPTransport<T>
         Ok(Async::Ready(Some(frame))) => {
           trace!("transport poll_recv; frame={:?}", frame);
           if let Err(e) = self.conn.handle_frame(frame) {
-            let err = format!("failed to handle frame: {:?}", e);
-            return Err(io::Error::new(io::ErrorKind::Other, err));
+            if e.hard_error().is_some() {
+              // send the connection.close queued by handle_frame
+              let _ = self.poll_send();
+              return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+            return Err(io::Error::from(::error::Error::from(e).context("failed to handle frame")));
           }
           got_frame = true;
         },
@@ -203,6 +290,10 @@ impl<T> AMQPTransport<T>
         },
         Err(e) => {
           error!("transport poll_recv; status=Err({:?})", e);
+          if let Some(e) = e.get_ref().and_then(|e| e.downcast_ref::<lapin_async::error::Error>()).filter(|e| e.hard_error().is_some()) {
+            self.conn.close_on_protocol_error(e);
+            let _ = self.poll_send();
+          }
           return Err(From::from(e));
         },
       };
@@ -227,9 +318,76 @@ impl<T> AMQPTransport<T>
     self.poll_complete()
   }
 
+  /// builds the error returned once the server closed the connection
+  ///
+  /// the `CloseReason` sent by the server can be obtained through `get_ref`
+  pub fn close_error(&self) -> Option<io::Error> {
+    self.conn.close_reason.clone().map(|reason| {
+      io::Error::new(io::ErrorKind::ConnectionAborted, reason)
+    })
+  }
+
+  /// builds the error returned by the futures waiting on a channel closed by the server
+  ///
+  /// the inner error is a `lapin_async::error::Error::ChannelClosed` with the reason sent by the server
+  pub fn channel_close_error(&self, channel_id: u16, serial: u64) -> Option<io::Error> {
+    self.conn.get_closed_channel_reason(channel_id, serial).map(|reason| {
+      io::Error::new(io::ErrorKind::Other, lapin_async::error::Error::ChannelClosed(reason))
+    })
+  }
+
+  /// builds the error returned by a handle on a channel that does not exist anymore
+  ///
+  /// returns None if the channel with this serial is still open. If its id was reused since,
+  /// the handle gets the error of its own channel instead of acting on the new one
+  pub fn channel_error(&self, channel_id: u16, serial: u64) -> Option<io::Error> {
+    if self.conn.get_serial(channel_id) == Some(serial) {
+      return None;
+    }
+    Some(self.channel_close_error(channel_id, serial).unwrap_or_else(|| {
+      io::Error::new(io::ErrorKind::Other, lapin_async::error::Error::InvalidChannel)
+    }))
+  }
+
   /// Register a consumer so that it gets notified when messages are ready
-  pub fn register_consumer(&mut self, consumer_tag: &str, consumer_task: task::Task) {
-    self.consumers.insert(consumer_tag.to_string(), consumer_task);
+  pub fn register_consumer(&mut self, channel_id: u16, consumer_tag: &str, consumer_task: task::Task) {
+    self.consumers.insert(consumer_tag.to_string(), (channel_id, consumer_task));
+  }
+
+  /// Register a stream of returned messages so that it gets notified when the server sends one back
+  ///
+  /// there is a single listener per channel, registering a new stream replaces the previous one.
+  /// Returns the id to give to `unregister_returns`
+  pub fn register_returns(&mut self, channel_id: u16, returns_task: task::Task) -> u64 {
+    self.listener_index += 1;
+    self.returns.insert(channel_id, (self.listener_index, returns_task));
+    self.listener_index
+  }
+
+  /// Register the stream of connection events so that it gets notified when one happens
+  ///
+  /// there is a single listener, registering a new stream replaces the previous one.
+  /// Returns the id to give to `unregister_events`
+  pub fn register_events(&mut self, events_task: task::Task) -> u64 {
+    self.listener_index += 1;
+    self.events = Some((self.listener_index, events_task));
+    self.listener_index
+  }
+}
+
+impl<T> AMQPTransport<T> {
+  /// Unregister a stream of returned messages, unless it was already replaced by another one
+  pub fn unregister_returns(&mut self, channel_id: u16, listener: u64) {
+    if self.returns.get(&channel_id).map(|r| r.0) == Some(listener) {
+      self.returns.remove(&channel_id);
+    }
+  }
+
+  /// Unregister the stream of connection events, unless it was already replaced by another one
+  pub fn unregister_events(&mut self, listener: u64) {
+    if self.events.as_ref().map(|e| e.0) == Some(listener) {
+      self.events = None;
+    }
   }
 }
 
@@ -244,9 +402,21 @@ impl<T> Stream for AMQPTransport<T>
       trace!("transport poll");
       if let Async::Ready(()) = self.poll_recv()? {
         trace!("poll transport; status=Ready");
+        if let Some(err) = self.close_error() {
+          return Err(err);
+        }
+        if self.conn.state == ConnectionState::Closed {
+          return Ok(Async::Ready(None));
+        }
         return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "The connection was closed by the remote peer"));
       }
-      self.poll_send().map(|r| r.map(Some))
+      let sent = self.poll_send()?;
+      if sent.is_ready() && self.conn.state == ConnectionState::Closed {
+        if let Some(err) = self.close_error() {
+          return Err(err);
+        }
+      }
+      Ok(sent.map(Some))
     }
 }
 
@@ -291,7 +461,13 @@ impl<T> Future for AMQPTransportConnector<T>
     transport.poll()?;
 
     trace!("connector poll; state=ConnectionState::{:?}", transport.conn.state);
+    // the server refused the credentials or the vhost, the close-ok was sent by `poll`
+    if let Some(err) = transport.close_error() {
+      return Err(err);
+    }
     if transport.conn.state == ConnectionState::Connected {
+      // frames from the server are now limited by the negotiated frame_max
+      transport.inbound_frame_max.store(transport.conn.configuration.frame_max as usize, Ordering::Relaxed);
       return Ok(Async::Ready(transport))
     }
 
@@ -325,7 +501,7 @@ mod tests {
   fn encode_multiple_frames() {
     let _ = env_logger::try_init();
 
-    let mut codec = AMQPCodec { frame_max: 8192 };
+    let mut codec = AMQPCodec { frame_max: 8192, inbound_frame_max: Default::default(), handshake: false };
     let mut buffer = BytesMut::with_capacity(8192);
     let r = codec.encode(Frame::Heartbeat(0), &mut buffer);
     assert_eq!(false, r.is_err());
@@ -344,7 +520,7 @@ mod tests {
 
     let _ = env_logger::try_init();
 
-    let mut codec = AMQPCodec { frame_max: 8192 };
+    let mut codec = AMQPCodec { frame_max: 8192, inbound_frame_max: Default::default(), handshake: false };
     let mut buffer = BytesMut::with_capacity(8192);
     let frame = Frame::Header(0, 10, ContentHeader {
       class_id: 10,
@@ -361,7 +537,7 @@ mod tests {
   fn encode_initial_extend_buffer() {
     let _ = env_logger::try_init();
 
-    let mut codec = AMQPCodec { frame_max: 8192 };
+    let mut codec = AMQPCodec { frame_max: 8192, inbound_frame_max: Default::default(), handshake: false };
     let frame_max = codec.frame_max as usize;
     let mut buffer = BytesMut::new();
 
@@ -375,7 +551,7 @@ mod tests {
   fn encode_anticipation_extend_buffer() {
     let _ = env_logger::try_init();
 
-    let mut codec = AMQPCodec { frame_max: 8192 };
+    let mut codec = AMQPCodec { frame_max: 8192, inbound_frame_max: Default::default(), handshake: false };
     let frame_max = codec.frame_max as usize;
     let mut buffer = BytesMut::new();
 
@@ -388,7 +564,7 @@ mod tests {
       // Use 80% of the remaining space (it shouldn't trigger buffer capacity expansion)
       .take(((buffer.capacity() as f64 - buffer.len() as f64) * 0.8) as usize)
       .collect::<Vec<u8>>();
-    let r = codec.encode(Frame::Body(1, payload), &mut buffer);
+    let r = codec.encode(Frame::Body(1, payload.into()), &mut buffer);
     assert_eq!(false, r.is_err());
     assert_eq!(frame_max * 2, buffer.capacity());
 
@@ -396,8 +572,98 @@ mod tests {
       // Use 80% of the remaining space (it should trigger a buffer capacity expansion)
       .take(((buffer.capacity() as f64 - buffer.len() as f64) * 0.8) as usize)
       .collect::<Vec<u8>>();
-    let r = codec.encode(Frame::Body(1, payload), &mut buffer);
+    let r = codec.encode(Frame::Body(1, payload.into()), &mut buffer);
     assert_eq!(false, r.is_err());
     assert_eq!(frame_max * 4, buffer.capacity());
   }
+
+  #[test]
+  fn decode_unsupported_protocol_version() {
+    let _ = env_logger::try_init();
+
+    let mut codec = AMQPCodec { frame_max: 8192, inbound_frame_max: Default::default(), handshake: true };
+    let mut buffer = BytesMut::from(&b"AMQ"[..]);
+    assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+
+    buffer.extend_from_slice(b"P\x01\x01\x00\x0a");
+    let err = codec.decode(&mut buffer).unwrap_err();
+    assert_eq!(err.to_string(), "the server does not support AMQP 0-9-1, it wants AMQP 0-10");
+  }
+
+  #[test]
+  fn decode_frame_too_large() {
+    let _ = env_logger::try_init();
+
+    let mut codec = AMQPCodec { frame_max: 4096, inbound_frame_max: Arc::new(AtomicUsize::new(4096)), handshake: false };
+    // body frame on channel 1 announcing 4088 bytes of payload, the largest allowed
+    let mut buffer = BytesMut::from(&[3, 0, 1, 0, 0, 0x0f, 0xf8][..]);
+    assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+    assert!(buffer.capacity() >= 4096);
+
+    let mut buffer = BytesMut::from(&[3, 0, 1, 0, 0, 0x0f, 0xf9][..]);
+    let err = codec.decode(&mut buffer).unwrap_err();
+    assert_eq!(err.get_ref().and_then(|e| e.downcast_ref::<lapin_async::error::Error>()), Some(&lapin_async::error::Error::FrameTooLarge(4097, 4096)));
+
+    // before the handshake, a header announcing 4GiB is rejected without reserving room for it
+    let mut codec = AMQPCodec { frame_max: 4096, inbound_frame_max: Default::default(), handshake: false };
+    let mut buffer = BytesMut::from(&[1, 0, 0, 0xff, 0xff, 0xff, 0xff][..]);
+    let err = codec.decode(&mut buffer).unwrap_err();
+    assert_eq!(err.get_ref().and_then(|e| e.downcast_ref::<lapin_async::error::Error>()), Some(&lapin_async::error::Error::FrameTooLarge(u32::MAX, FRAME_MIN_SIZE)));
+    assert!(buffer.capacity() < 4096);
+  }
+
+  #[test]
+  fn decode_invalid_frames() {
+    let _ = env_logger::try_init();
+
+    let decode_error = |data: &[u8]| {
+      let mut codec = AMQPCodec { frame_max: 8192, inbound_frame_max: Default::default(), handshake: false };
+      let err = codec.decode(&mut BytesMut::from(data)).unwrap_err();
+      assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+      err.get_ref().and_then(|e| e.downcast_ref::<lapin_async::error::Error>()).cloned()
+    };
+
+    assert_eq!(decode_error(&[42, 0, 1, 0, 0, 0, 0, 206]), Some(lapin_async::error::Error::UnknownFrameType(42)));
+    assert_eq!(decode_error(&[3, 0, 1, 0, 0, 0, 1, b'x', 0]), Some(lapin_async::error::Error::InvalidFrameEnd(0)));
+    assert_eq!(decode_error(&[1, 0, 1, 0, 0, 0, 4, 0, 20, 0, 20, 206]), Some(lapin_async::error::Error::InvalidFramePayload(1)));
+  }
+
+  #[test]
+  fn decode_not_amqp_server() {
+    let _ = env_logger::try_init();
+
+    let mut codec = AMQPCodec { frame_max: 8192, inbound_frame_max: Default::default(), handshake: true };
+    let err = codec.decode(&mut BytesMut::from(&b"HTTP/1.1 400 Bad Request\r\n"[..])).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(err.get_ref().and_then(|e| e.downcast_ref::<lapin_async::error::Error>()), Some(&lapin_async::error::Error::NotAMQPServer));
+
+    // once a frame was received, a protocol header is not expected anymore
+    let mut codec = AMQPCodec { frame_max: 8192, inbound_frame_max: Default::default(), handshake: true };
+    let mut buffer = BytesMut::from(&[8, 0, 0, 0, 0, 0, 0, 206][..]);
+    buffer.extend_from_slice(b"HTTP/1.1 400 Bad Request\r\n");
+    assert_eq!(codec.decode(&mut buffer).unwrap(), Some(Frame::Heartbeat(0)));
+    let err = codec.decode(&mut buffer).unwrap_err();
+    assert_eq!(err.get_ref().and_then(|e| e.downcast_ref::<lapin_async::error::Error>()), Some(&lapin_async::error::Error::UnknownFrameType(b'H')));
+  }
+
+  #[test]
+  fn decode_body_frame_without_copy() {
+    let _ = env_logger::try_init();
+
+    let mut codec = AMQPCodec { frame_max: 8192, inbound_frame_max: Default::default(), handshake: false };
+    let payload = vec![b'x'; 64];
+    let mut buffer = BytesMut::from(&[3, 0, 1, 0, 0, 0, 64][..]);
+    buffer.extend_from_slice(&payload);
+    buffer.extend_from_slice(&[206, 8]);
+    let start = buffer.as_ptr() as usize;
+    match codec.decode(&mut buffer).unwrap() {
+      Some(Frame::Body(1, data)) => {
+        assert_eq!(data, payload);
+        // the payload points into the read buffer
+        assert_eq!(data.as_ptr() as usize, start + 7);
+      },
+      f => panic!("expected a body frame, got {:?}", f),
+    }
+    assert_eq!(&buffer[..], &[8][..]);
+  }
 }
@@ -10,6 +10,7 @@ use tokio::net::TcpStream;
 
 use lapin::types::FieldTable;
 use lapin::client::ConnectionOptions;
+use lapin::error::Error;
 use lapin::channel::{BasicConsumeOptions,BasicPublishOptions,BasicQosOptions,BasicProperties,QueueDeclareOptions,QueueDeleteOptions,QueuePurgeOptions};
 
 #[test]
@@ -19,7 +20,7 @@ fn connection() {
   let addr = std::env::var("AMQP_ADDR").unwrap_or_else(|_| "127.0.0.1:5672".to_string()).parse().unwrap();
 
   tokio::run(
-    TcpStream::connect(&addr).and_then(|stream| {
+    TcpStream::connect(&addr).map_err(Error::from).and_then(|stream| {
       lapin::client::Client::connect(stream, ConnectionOptions::default())
     }).and_then(|(client, _)| {
 
@@ -31,7 +32,7 @@ fn connection() {
           info!("channel {} declared queue {}", id, "hello");
 
           channel.queue_purge("hello", QueuePurgeOptions::default()).and_then(move |_| {
-            channel.basic_publish("", "hello", b"hello from tokio", BasicPublishOptions::default(), BasicProperties::default())
+            channel.basic_publish("", "hello", b"hello from tokio".to_vec(), BasicPublishOptions::default(), BasicProperties::default())
           })
         })
       }).and_then(move |_| {
@@ -55,7 +56,7 @@ fn connection() {
           stream.into_future().map_err(|(err, _)| err).and_then(move |(message, _)| {
             let msg = message.unwrap();
             info!("got message: {:?}", msg);
-            assert_eq!(msg.data, b"hello from tokio");
+            assert_eq!(msg.data, &b"hello from tokio"[..]);
             ch1.basic_ack(msg.delivery_tag)
           }).and_then(move |_| {
             ch2.queue_delete("hello", QueueDeleteOptions::default())